@@ -0,0 +1,89 @@
+//! Compares the intersection and difference kernels with looking every ID up by binary search.
+//!
+//! Run with `cargo bench`.
+use rtag::kernels;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// Sorted and deduplicated random IDs, about `n` of them spread over `0..range`
+fn random_ids(seed: u64, n: usize, range: u32) -> Vec<u32> {
+    let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+    let mut ids: Vec<u32> = (0..n)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % range as u64) as u32
+        })
+        .collect();
+    ids.sort_unstable();
+    ids.dedup();
+    ids
+}
+
+fn probe_intersect(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    out.extend(a.iter().filter(|x| b.binary_search(x).is_ok()));
+}
+
+fn probe_difference(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    out.extend(a.iter().filter(|x| b.binary_search(x).is_err()));
+}
+
+type Kernel = fn(&[u32], &[u32], &mut Vec<u32>);
+
+/// Average time of one run, repeating it for at least 200ms
+fn time(kernel: Kernel, a: &[u32], b: &[u32]) -> (Duration, usize) {
+    let mut out = Vec::with_capacity(a.len());
+    let mut runs = 0;
+    let start = Instant::now();
+    while runs == 0 || start.elapsed() < Duration::from_millis(200) {
+        out.clear();
+        kernel(black_box(a), black_box(b), &mut out);
+        black_box(&out);
+        runs += 1;
+    }
+    (start.elapsed() / runs, out.len())
+}
+
+fn main() {
+    let intersections: [(&str, Kernel); 5] = [
+        ("probe", probe_intersect),
+        ("merge", kernels::intersect_merge),
+        ("gallop", kernels::intersect_gallop),
+        ("blocks", kernels::intersect_blocks),
+        ("auto", kernels::intersect),
+    ];
+    let differences: [(&str, Kernel); 5] = [
+        ("probe", probe_difference),
+        ("merge", kernels::difference_merge),
+        ("gallop", kernels::difference_gallop),
+        ("blocks", kernels::difference_blocks),
+        ("auto", kernels::difference),
+    ];
+    let range = 2_000_000;
+    let sizes = [
+        (1_000, 1_000),
+        (100_000, 100_000),
+        (1_000_000, 1_000_000),
+        (10_000, 100_000),
+        (1_000, 1_000_000),
+        (100, 1_000_000),
+        (1_000_000, 1_000),
+    ];
+
+    for (op, kernels) in [("intersect", &intersections), ("difference", &differences)] {
+        println!("{}", op);
+        for &(n, m) in &sizes {
+            let a = random_ids(1, n, range);
+            let b = random_ids(2, m, range);
+            print!("  {:>8} x {:<8}", a.len(), b.len());
+            let mut expected = None;
+            for (name, kernel) in kernels {
+                let (elapsed, len) = time(*kernel, &a, &b);
+                assert_eq!(*expected.get_or_insert(len), len, "{} disagrees", name);
+                print!("  {} {:>10.1?}", name, elapsed);
+            }
+            println!();
+        }
+    }
+}
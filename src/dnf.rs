@@ -1,29 +1,85 @@
-use crate::{qry::Expr, qry::DNF, TagName};
+use crate::qry::{atleast_sets, Atom, Expr, DNF};
+
+/// Number of conjunctions `to_dnf` would produce, without building them
+pub fn dnf_size(expr: &Expr) -> usize {
+    use Expr::*;
+    /// Sizes of the expression and of its negation, computed together so `^` stays linear
+    fn size(expr: &Expr) -> (usize, usize) {
+        match expr {
+            Tag(_) | Glob(_) | Value(_) | AtLeast(..) => (1, 1),
+            Not(x) => {
+                let (pos, neg) = size(x);
+                (neg, pos)
+            }
+            // negated ANDs become ORs once the negations are lowered, and the other way around
+            And(l, r) => {
+                let ((lp, ln), (rp, rn)) = (size(l), size(r));
+                (lp.saturating_mul(rp), ln.saturating_add(rn))
+            }
+            Or(l, r) => {
+                let ((lp, ln), (rp, rn)) = (size(l), size(r));
+                (lp.saturating_add(rp), ln.saturating_mul(rn))
+            }
+            // `l ^ r` is `(l & !r) | (!l & r)`, its negation `(l & r) | (!l & !r)`
+            Xor(l, r) => {
+                let ((lp, ln), (rp, rn)) = (size(l), size(r));
+                (
+                    lp.saturating_mul(rn).saturating_add(ln.saturating_mul(rp)),
+                    lp.saturating_mul(rp).saturating_add(ln.saturating_mul(rn)),
+                )
+            }
+        }
+    }
+    size(expr).0
+}
 
 pub fn to_dnf(expr: Expr) -> DNF {
     use Expr::*;
     fn lower_negs(expr: Expr) -> Expr {
         match expr {
-            Tag(_) => expr,
+            Tag(_) | Value(_) | AtLeast(..) => expr,
+            Glob(_) => unreachable!("globs should be expanded"),
             Not(v) => match *v {
-                Tag(_) => Not(v),
-                Not(x) => *x,
+                Tag(_) | Value(_) | AtLeast(..) => Not(v),
+                Glob(_) => unreachable!("globs should be expanded"),
+                Not(x) => lower_negs(*x),
                 And(l, r) => Or(Box::new(lower_negs(Not(l))), Box::new(lower_negs(Not(r)))),
                 Or(l, r) => And(Box::new(lower_negs(Not(l))), Box::new(lower_negs(Not(r)))),
+                // `!(l ^ r)` is `(l & r) | (!l & !r)`
+                Xor(l, r) => Or(
+                    Box::new(And(
+                        Box::new(lower_negs((*l).clone())),
+                        Box::new(lower_negs((*r).clone())),
+                    )),
+                    Box::new(And(
+                        Box::new(lower_negs(Not(l))),
+                        Box::new(lower_negs(Not(r))),
+                    )),
+                ),
             },
             And(l, r) => And(Box::new(lower_negs(*l)), Box::new(lower_negs(*r))),
             Or(l, r) => Or(Box::new(lower_negs(*l)), Box::new(lower_negs(*r))),
+            // `l ^ r` is `(l & !r) | (!l & r)`
+            Xor(l, r) => Or(
+                Box::new(And(
+                    Box::new(lower_negs((*l).clone())),
+                    Box::new(lower_negs(Not(r.clone()))),
+                )),
+                Box::new(And(Box::new(lower_negs(Not(l))), Box::new(lower_negs(*r)))),
+            ),
         }
     }
 
     fn to_dnf_inner(expr: Expr) -> Expr {
         match expr {
-            e @ Tag(_) => e,
+            e @ (Tag(_) | Value(_) | AtLeast(..)) => e,
+            Glob(_) => unreachable!("globs should be expanded"),
             Not(v) => match *v {
-                Tag(_) => Not(v),
+                Tag(_) | Value(_) | AtLeast(..) => Not(v),
                 _ => unreachable!("NOTs should be lowered"),
             },
             Or(l, r) => Or(Box::new(to_dnf_inner(*l)), Box::new(to_dnf_inner(*r))),
+            Xor(..) => unreachable!("XORs should be lowered"),
             And(l, r) => match (to_dnf_inner(*l), to_dnf_inner(*r)) {
                 (Or(l1, l2), v) | (v, Or(l1, l2)) => Or(
                     Box::new(to_dnf_inner(And(l1, Box::new(v.clone())))),
@@ -35,11 +91,15 @@ pub fn to_dnf(expr: Expr) -> DNF {
     }
 
     fn dnf_flatten(expr: Expr) -> DNF {
-        fn collect_ands(expr: Expr, v: &mut Vec<(TagName, bool)>) {
+        fn collect_ands(expr: Expr, v: &mut Vec<(Atom, bool)>) {
             match expr {
-                Tag(x) => v.push((x, true)),
+                Tag(x) => v.push((Atom::Tag(x), true)),
+                Value(x) => v.push((Atom::Value(x), true)),
+                AtLeast(n, args) => v.push((Atom::AtLeast(n, atleast_sets(&args)), true)),
                 Not(t) => match *t {
-                    Tag(x) => v.push((x, false)),
+                    Tag(x) => v.push((Atom::Tag(x), false)),
+                    Value(x) => v.push((Atom::Value(x), false)),
+                    AtLeast(n, args) => v.push((Atom::AtLeast(n, atleast_sets(&args)), false)),
                     _ => unreachable!("NOTs should be lowered"),
                 },
                 And(l, r) => {
@@ -47,6 +107,8 @@ pub fn to_dnf(expr: Expr) -> DNF {
                     collect_ands(*r, v);
                 }
                 Or(_, _) => unreachable!("CNF means no ORs under ANDs"),
+                Xor(..) => unreachable!("XORs should be lowered"),
+                Glob(_) => unreachable!("globs should be expanded"),
             }
         }
 
@@ -69,13 +131,5 @@ pub fn to_dnf(expr: Expr) -> DNF {
         emptycnf
     }
 
-    let lowered = lower_negs(expr);
-    if std::env::var("DEBUG").is_ok() {
-        eprintln!("lowered: {:?}", lowered);
-    }
-    let dnf_expr = to_dnf_inner(lowered);
-    if std::env::var("DEBUG").is_ok() {
-        eprintln!("dnf_expr: {:?}", dnf_expr);
-    }
-    dnf_flatten(dnf_expr)
+    dnf_flatten(to_dnf_inner(lower_negs(expr)))
 }
@@ -0,0 +1,107 @@
+use crate::lock::LockTimeout;
+use crate::page::Cursor;
+use crate::parse::ParseError;
+use crate::TagName;
+use std::fmt::{Display, Formatter};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An I/O operation on the store failed
+    Io {
+        context: &'static str,
+        source: std::io::Error,
+    },
+    /// A store file does not have the expected layout
+    Corrupted(&'static str),
+    /// The query could not be parsed
+    Parse(ParseError),
+    /// The value is longer than what the store can hold
+    ValueTooLong(usize),
+    /// The tag name is empty
+    InvalidTagName(TagName),
+    /// Another process held the store for too long
+    Lock(LockTimeout),
+    /// No store location could be resolved
+    NoStore,
+    /// The cursor does not belong to any value
+    InvalidCursor(Cursor),
+    /// Saved query names must be usable as `@name` in queries
+    InvalidSavedName(String),
+    /// An empty query cannot be saved
+    EmptySavedQuery,
+    /// The store was written by an older rtag and must be migrated first
+    OldFormat,
+    /// The store was written by a newer rtag, with this format version
+    NewerFormat(u32),
+}
+
+/// Wraps an io error with what we were trying to do, to be used with `map_err`
+pub(crate) fn io(context: &'static str) -> impl FnOnce(std::io::Error) -> Error {
+    move |source| Error::Io { context, source }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io { context, source } => write!(f, "{}: {}", context, source),
+            Error::Corrupted(what) => write!(f, "store is corrupted: {}", what),
+            Error::Parse(msg) => write!(f, "invalid query: {}", msg),
+            Error::ValueTooLong(len) => write!(
+                f,
+                "value too long: {} bytes, max is {} bytes",
+                len,
+                crate::write::MAX_VALUE_LENGTH
+            ),
+            Error::InvalidTagName(tag) => write!(
+                f,
+                "invalid tag name {:?}: tags must be non-empty",
+                tag.0
+            ),
+            Error::Lock(e) => e.fmt(f),
+            Error::NoStore => write!(
+                f,
+                "no store found: pass --store, or define RTAG_HOME or HOME in env"
+            ),
+            Error::InvalidCursor(cursor) => write!(f, "no value has the cursor {}", cursor),
+            Error::InvalidSavedName(name) => write!(
+                f,
+                "invalid saved query name {:?}: names are made of letters, digits, `_`, `-` and `/`",
+                name
+            ),
+            Error::EmptySavedQuery => write!(f, "cannot save an empty query"),
+            Error::OldFormat => write!(
+                f,
+                "store was created by an older rtag: run `rtag migrate` to upgrade it"
+            ),
+            Error::NewerFormat(version) => write!(
+                f,
+                "store has format version {} but this rtag only reads version {}: upgrade rtag",
+                version,
+                crate::format::FORMAT_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(e: ParseError) -> Self {
+        Error::Parse(e)
+    }
+}
+
+impl From<LockTimeout> for Error {
+    fn from(e: LockTimeout) -> Self {
+        Error::Lock(e)
+    }
+}
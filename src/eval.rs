@@ -0,0 +1,225 @@
+use crate::error::Result;
+use crate::kernels::{self, as_u32, into_ids};
+use crate::postings::PostingList;
+use crate::qry::{atleast_sets, execute_atleast, values_match, Expr, TagCtx, ValueMatch};
+use crate::{TagName, ID};
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+/// Evaluates the expression tree bottom up with set operations,
+/// so the query size stays linear where the DNF could grow exponentially.
+/// Complements are kept symbolic, `!a` never materializes every value.
+enum Set {
+    /// The IDs in the list, sorted
+    Pos(Vec<ID>),
+    /// Every ID but the ones in the list, sorted
+    Neg(Vec<ID>),
+}
+
+fn intersect(a: &[ID], b: &[ID]) -> Vec<ID> {
+    let mut out = Vec::with_capacity(a.len().min(b.len()));
+    kernels::intersect(as_u32(a), as_u32(b), &mut out);
+    into_ids(out)
+}
+
+fn union(a: &[ID], b: &[ID]) -> Vec<ID> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => {
+                out.push(a[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                out.push(b[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                out.push(a[i]);
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&a[i..]);
+    out.extend_from_slice(&b[j..]);
+    out
+}
+
+/// `a` without the IDs of `b`
+fn difference(a: &[ID], b: &[ID]) -> Vec<ID> {
+    let mut out = Vec::with_capacity(a.len());
+    kernels::difference(as_u32(a), as_u32(b), &mut out);
+    into_ids(out)
+}
+
+/// The IDs in exactly one of `a` and `b`
+fn symmetric_difference(a: &[ID], b: &[ID]) -> Vec<ID> {
+    let mut out = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        match a[i].cmp(&b[j]) {
+            Ordering::Less => {
+                out.push(a[i]);
+                i += 1;
+            }
+            Ordering::Greater => {
+                out.push(b[j]);
+                j += 1;
+            }
+            Ordering::Equal => {
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    out.extend_from_slice(&a[i..]);
+    out.extend_from_slice(&b[j..]);
+    out
+}
+
+fn and(l: Set, r: Set) -> Set {
+    use Set::*;
+    match (l, r) {
+        (Pos(a), Pos(b)) => Pos(intersect(&a, &b)),
+        (Pos(a), Neg(b)) | (Neg(b), Pos(a)) => Pos(difference(&a, &b)),
+        (Neg(a), Neg(b)) => Neg(union(&a, &b)),
+    }
+}
+
+fn or(l: Set, r: Set) -> Set {
+    use Set::*;
+    match (l, r) {
+        (Pos(a), Pos(b)) => Pos(union(&a, &b)),
+        (Pos(a), Neg(b)) | (Neg(b), Pos(a)) => Neg(difference(&b, &a)),
+        (Neg(a), Neg(b)) => Neg(intersect(&a, &b)),
+    }
+}
+
+/// `a ^ !b` is `!(a ^ b)` and `!a ^ !b` is `a ^ b`
+fn xor(l: Set, r: Set) -> Set {
+    use Set::*;
+    match (l, r) {
+        (Pos(a), Pos(b)) | (Neg(a), Neg(b)) => Pos(symmetric_difference(&a, &b)),
+        (Pos(a), Neg(b)) | (Neg(a), Pos(b)) => Neg(symmetric_difference(&a, &b)),
+    }
+}
+
+fn not(s: Set) -> Set {
+    match s {
+        Set::Pos(x) => Set::Neg(x),
+        Set::Neg(x) => Set::Pos(x),
+    }
+}
+
+/// A value predicate, possibly negated, which is cheaper to check on a few candidates
+fn as_value_filter(expr: &Expr) -> Option<(&ValueMatch, bool)> {
+    match expr {
+        Expr::Value(p) => Some((p, true)),
+        Expr::Not(x) => match &**x {
+            Expr::Value(p) => Some((p, false)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn all_ids(ctx: &TagCtx) -> Result<Vec<ID>> {
+    Ok(PostingList::new(&ctx.allmap)?.iter().collect())
+}
+
+fn filter(ctx: &TagCtx, ids: Vec<ID>, pred: (&ValueMatch, bool)) -> Result<Vec<ID>> {
+    let mut out = Vec::with_capacity(ids.len());
+    for id in ids {
+        if values_match(ctx, id, &[pred])? {
+            out.push(id);
+        }
+    }
+    Ok(out)
+}
+
+fn eval(ctx: &TagCtx, expr: &Expr) -> Result<Set> {
+    Ok(match expr {
+        Expr::Tag(tag) => Set::Pos(match ctx.mapped_tags.get(tag) {
+            Some(m) => {
+                let p = PostingList::new(m)?;
+                match p.as_array() {
+                    Some(bytes) => into_ids(kernels::ids(bytes).into_owned()),
+                    None => p.iter().collect(),
+                }
+            }
+            None => vec![],
+        }),
+        Expr::Value(p) => Set::Pos(filter(ctx, all_ids(ctx)?, (p, true))?),
+        Expr::AtLeast(n, args) => Set::Pos(execute_atleast(ctx, *n, &atleast_sets(args))?),
+        Expr::Glob(_) => unreachable!("globs should be expanded"),
+        Expr::Not(x) => not(eval(ctx, x)?),
+        Expr::Or(l, r) => or(eval(ctx, l)?, eval(ctx, r)?),
+        Expr::Xor(l, r) => xor(eval(ctx, l)?, eval(ctx, r)?),
+        Expr::And(l, r) => {
+            // value predicates only look at the values the other side kept
+            let filtered = match (as_value_filter(l), as_value_filter(r)) {
+                (Some(pred), None) => Some((pred, r)),
+                (None, Some(pred)) => Some((pred, l)),
+                _ => None,
+            };
+            match filtered {
+                Some((pred, other)) => match eval(ctx, other)? {
+                    Set::Pos(ids) => Set::Pos(filter(ctx, ids, pred)?),
+                    Set::Neg(excluded) => {
+                        Set::Pos(filter(ctx, difference(&all_ids(ctx)?, &excluded), pred)?)
+                    }
+                },
+                None => and(eval(ctx, l)?, eval(ctx, r)?),
+            }
+        }
+    })
+}
+
+fn collect_tags(expr: &Expr, tags: &mut BTreeSet<TagName>) {
+    match expr {
+        Expr::Tag(tag) => {
+            tags.insert(tag.clone());
+        }
+        Expr::AtLeast(_, args) => args.iter().for_each(|x| collect_tags(x, tags)),
+        Expr::Not(x) => collect_tags(x, tags),
+        Expr::And(l, r) | Expr::Or(l, r) | Expr::Xor(l, r) => {
+            collect_tags(l, tags);
+            collect_tags(r, tags);
+        }
+        Expr::Value(_) | Expr::Glob(_) => {}
+    }
+}
+
+/// Tags appearing in the expression
+pub fn expr_tags(expr: &Expr) -> BTreeSet<TagName> {
+    let mut tags = BTreeSet::new();
+    collect_tags(expr, &mut tags);
+    tags
+}
+
+/// Executes an expanded query without going through the DNF, returning every match in order
+pub fn execute(ctx: &TagCtx, expr: &Expr) -> Result<Vec<ID>> {
+    Ok(match eval(ctx, expr)? {
+        Set::Pos(ids) => ids,
+        Set::Neg(excluded) => difference(&all_ids(ctx)?, &excluded),
+    })
+}
+
+/// Number of values matching an expanded query, without reading values unless the query filters on them.
+/// Complements are counted as `|all| - |excluded|` rather than listed.
+pub fn count(ctx: &TagCtx, expr: &Expr) -> Result<usize> {
+    let all = PostingList::new(&ctx.allmap)?.len();
+    Ok(match expr {
+        Expr::Tag(tag) => match ctx.mapped_tags.get(tag) {
+            Some(m) => PostingList::new(m)?.len(),
+            None => 0,
+        },
+        Expr::Not(x) => all.saturating_sub(count(ctx, x)?),
+        _ => match eval(ctx, expr)? {
+            Set::Pos(ids) => ids.len(),
+            Set::Neg(excluded) => all.saturating_sub(excluded.len()),
+        },
+    })
+}
@@ -0,0 +1,72 @@
+use crate::page::Cursor;
+use crate::plan::QueryPlan;
+use crate::qry::{Expr, DNF};
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+/// How a query was run, as printed by `rtag qry --explain`
+#[derive(Debug)]
+pub struct Explain {
+    /// The query as parsed, None if it is empty
+    pub ast: Option<Expr>,
+    /// The query once tags include their descendants and globs are replaced by the tags they match
+    pub expanded: Option<Expr>,
+    /// The disjunctive normal form, None if the expression tree was evaluated directly
+    pub dnf: Option<DNF>,
+    pub plan: QueryPlan,
+    /// Time spent in each phase, in order
+    pub timings: Vec<(&'static str, Duration)>,
+    /// Number of values returned
+    pub matches: usize,
+    /// Resumes after the last value returned, None if nothing was returned
+    pub next: Option<Cursor>,
+    last: Instant,
+}
+
+impl Explain {
+    pub(crate) fn new() -> Self {
+        Explain {
+            ast: None,
+            expanded: None,
+            dnf: None,
+            plan: QueryPlan::All,
+            timings: vec![],
+            matches: 0,
+            next: None,
+            last: Instant::now(),
+        }
+    }
+
+    /// Records the time spent since the previous phase ended
+    pub(crate) fn phase(&mut self, name: &'static str) {
+        let now = Instant::now();
+        self.timings.push((name, now - self.last));
+        self.last = now;
+    }
+}
+
+impl Display for Explain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.ast {
+            Some(ast) => writeln!(f, "query:    {}", ast)?,
+            None => writeln!(f, "query:    (empty)")?,
+        }
+        if let Some(expanded) = &self.expanded {
+            writeln!(f, "expanded: {}", expanded)?;
+        }
+        if let Some(dnf) = &self.dnf {
+            writeln!(f, "dnf:      {}", dnf)?;
+        }
+        writeln!(f, "plan:")?;
+        for line in self.plan.to_string().lines() {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "timings:")?;
+        for (phase, time) in &self.timings {
+            writeln!(f, "  {:<8} {:?}", phase, time)?;
+        }
+        let total: Duration = self.timings.iter().map(|(_, t)| *t).sum();
+        writeln!(f, "  {:<8} {:?}", "total", total)?;
+        writeln!(f, "{} values", self.matches)
+    }
+}
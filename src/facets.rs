@@ -0,0 +1,59 @@
+use crate::error::Result;
+use crate::kernels::{self, as_u32, GALLOP_RATIO};
+use crate::postings::PostingList;
+use crate::qry::matching_ids;
+use crate::tagname::{list_tags, tag_file};
+use crate::write::open_tagmap;
+use crate::{TagName, ID};
+use std::path::PathBuf;
+
+/// The tags found among the values matching the query, with how many of them have each,
+/// most frequent first. Tags named in the query are left out, and so are the ones not
+/// starting with `prefix` when given.
+pub fn facets(
+    root: &mut PathBuf,
+    qry: &str,
+    prefix: Option<&str>,
+    limit: usize,
+) -> Result<Vec<(TagName, usize)>> {
+    let (ids, named) = matching_ids(root, qry)?;
+    let ids = as_u32(&ids);
+
+    let mut counts = vec![];
+    let mut common = Vec::with_capacity(ids.len());
+    for tag in list_tags(root)? {
+        if named.contains(&tag) || prefix.is_some_and(|p| !tag.0.starts_with(p)) {
+            continue;
+        }
+        let map = match tag_file(&tag) {
+            Some(name) => open_tagmap(root, &name)?,
+            None => None,
+        };
+        let Some(map) = map else {
+            continue;
+        };
+        let list = PostingList::new(&map)?;
+        let count = match list.as_array() {
+            Some(bytes) => {
+                common.clear();
+                kernels::intersect(ids, &kernels::ids(bytes), &mut common);
+                common.len()
+            }
+            // probing the few matches beats decoding the whole list
+            None if list.is_bitmap() || ids.len().saturating_mul(GALLOP_RATIO) < list.len() => {
+                ids.iter().filter(|&&id| list.contains(ID(id))).count()
+            }
+            None => {
+                common.clear();
+                kernels::intersect(ids, &list.to_vec(), &mut common);
+                common.len()
+            }
+        };
+        if count > 0 {
+            counts.push((tag, count));
+        }
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(limit);
+    Ok(counts)
+}
@@ -0,0 +1,335 @@
+use crate::error::{io, Error, Result};
+use crate::kernels::ids;
+use crate::lock::LockKind;
+use crate::postings;
+use crate::qry::read_int;
+use crate::saved::SAVED_DIR;
+use crate::tagname::{decode_v2, file_name, save_name, tag_file, TAGS_DIR};
+use crate::write::write_atomic;
+use crate::TagName;
+use std::path::{Path, PathBuf};
+
+/// Version of the on-disk layout, kept in `__version` so rtag never misreads a store
+/// written by another version of itself.
+///
+/// Stores without the file predate it: they are either fresh, or were written by
+/// the original rtag (version 1) which kept fixed size records in `__data`,
+/// the raw IDs in `__all` and its tag files next to them.
+/// Version 2 kept uppercase letters as is in tag and saved query file names.
+pub const FORMAT_VERSION: u32 = 3;
+const VERSION_FILE: &str = "__version";
+
+/// Migrations build the new files aside, then move them in place once `DONE_FILE` is written,
+/// so an interrupted migration either starts over or finishes moving them
+const MIGRATE_DIR: &str = "migrate.tmp";
+const DONE_FILE: &str = "__done";
+
+/// Version 1 records: `[len: u8][value, zero padded][id: u32]`
+const V1_RECORD_SIZE: usize = 256;
+
+fn read_version(root: &Path) -> Result<Option<u32>> {
+    match std::fs::read_to_string(root.join(VERSION_FILE)) {
+        Ok(x) => x
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| Error::Corrupted("version file does not hold a number")),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io {
+            context: "could not read version file",
+            source: e,
+        }),
+    }
+}
+
+/// Marks the store as having the current layout, creating the dirs it needs
+fn write_version(root: &Path) -> Result<()> {
+    std::fs::create_dir_all(root.join(TAGS_DIR)).map_err(io("failed creating tags dir"))?;
+    write_atomic(root, VERSION_FILE, FORMAT_VERSION.to_string().as_bytes())
+}
+
+/// Whether no value was ever written to the store
+fn is_fresh(root: &Path) -> bool {
+    std::fs::metadata(root.join("__data")).map_or(true, |m| m.len() == 0)
+}
+
+/// Version of the store's layout, guessed from its files when it has no version file
+fn version(root: &Path) -> Result<u32> {
+    if let Some(v) = read_version(root)? {
+        return Ok(v);
+    }
+    if is_fresh(root) {
+        return Ok(FORMAT_VERSION);
+    }
+    // values moved to `__offsets` along with the version 2 layout, unless a migration is underway
+    if root.join("__offsets").exists() && !root.join(MIGRATE_DIR).exists() {
+        return Ok(2);
+    }
+    Ok(1)
+}
+
+/// Fails if the store has another layout, fresh stores get the current one on their first write.
+/// Must be called with a lock held, before anything else touches the store.
+pub fn check(root: &Path, kind: LockKind) -> Result<()> {
+    match read_version(root)? {
+        Some(v) if v == FORMAT_VERSION => Ok(()),
+        Some(v) if v > FORMAT_VERSION => Err(Error::NewerFormat(v)),
+        None if is_fresh(root) => {
+            if kind == LockKind::Exclusive {
+                write_version(root)?;
+            }
+            Ok(())
+        }
+        _ => Err(Error::OldFormat),
+    }
+}
+
+/// Upgrades the store to the current layout, returns the version it had.
+/// Must be called with an exclusive lock held.
+pub fn migrate(root: &mut PathBuf) -> Result<u32> {
+    let from = version(root)?;
+    if from > FORMAT_VERSION {
+        return Err(Error::NewerFormat(from));
+    }
+    if from < 2 {
+        from_v1(root)?;
+    }
+    if from < 3 {
+        from_v2(root, TAGS_DIR)?;
+        from_v2(root, SAVED_DIR)?;
+    }
+    write_version(root)?;
+    Ok(from)
+}
+
+fn from_v1(root: &mut PathBuf) -> Result<()> {
+    if !root.join(MIGRATE_DIR).join(DONE_FILE).exists() {
+        build_v1(root)?;
+    }
+
+    // the old tag files are copied over, and one of them may be named like the tags dir
+    for name in v1_tag_files(root)? {
+        std::fs::remove_file(root.join(name)).map_err(io("could not remove old tag file"))?;
+    }
+    // earlier versions of rtag created an empty tags dir along with the store
+    let _ = std::fs::remove_dir(root.join(TAGS_DIR));
+    for name in ["__data", "__offsets", "__all", TAGS_DIR] {
+        let from = root.join(MIGRATE_DIR).join(name);
+        if from.exists() {
+            std::fs::rename(from, root.join(name)).map_err(io("could not move migrated file"))?;
+        }
+    }
+    // rebuilt from the new files on first use
+    let _ = std::fs::remove_file(root.join("__index"));
+    std::fs::remove_dir_all(root.join(MIGRATE_DIR)).map_err(io("could not remove migration dir"))
+}
+
+/// Renames the files of `dir` to escape uppercase letters, or after the hash of their name
+/// when it gets too long once escaped.
+/// Files already renamed by an interrupted migration are left as they are.
+fn from_v2(root: &mut PathBuf, dir: &str) -> Result<()> {
+    root.push(dir);
+    let entries = std::fs::read_dir(&root);
+    root.pop();
+    let entries = match entries {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(Error::Io {
+                context: "cannot read dir to migrate",
+                source: e,
+            })
+        }
+    };
+    for file in entries {
+        let file = file.map_err(io("cannot read dir to migrate"))?;
+        let Ok(name) = file.file_name().into_string() else {
+            continue;
+        };
+        let Some(decoded) = decode_v2(&name) else {
+            continue;
+        };
+        let Some(renamed) = file_name(&decoded) else {
+            continue;
+        };
+        if renamed != name {
+            let renamed = format!("{}/{}", dir, renamed);
+            save_name(root, &renamed, &decoded)?;
+            std::fs::rename(file.path(), root.join(renamed))
+                .map_err(io("could not rename file"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 1 tag files are every regular file at the root not named like an internal file
+fn v1_tag_files(root: &Path) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for file in std::fs::read_dir(root).map_err(io("cannot read store dir"))? {
+        let file = file.map_err(io("cannot read store dir"))?;
+        if !file.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        match file.file_name().into_string() {
+            Ok(name) if !name.starts_with("__") => names.push(name),
+            _ => {}
+        }
+    }
+    Ok(names)
+}
+
+fn read_v1(root: &Path, name: &str) -> Result<Vec<u8>> {
+    match std::fs::read(root.join(name)) {
+        Ok(x) => Ok(x),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(Error::Io {
+            context: "could not read old store file",
+            source: e,
+        }),
+    }
+}
+
+/// Raw sorted IDs, what `__all` and tag files held in version 1
+fn v1_postings(bytes: &[u8]) -> Result<Vec<u8>> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(Error::Corrupted("old tag file has a partial ID"));
+    }
+    Ok(postings::encode(&ids(bytes)))
+}
+
+fn build_v1(root: &mut PathBuf) -> Result<()> {
+    root.push(MIGRATE_DIR);
+    let _ = std::fs::remove_dir_all(&root);
+    root.push(TAGS_DIR);
+    let created = std::fs::create_dir_all(&root);
+    root.pop();
+    root.pop();
+    created.map_err(io("could not create migration dir"))?;
+    let moved = |name: &str| format!("{}/{}", MIGRATE_DIR, name);
+
+    let old = read_v1(root, "__data")?;
+    if !old.len().is_multiple_of(V1_RECORD_SIZE) {
+        return Err(Error::Corrupted("old data file has a partial record"));
+    }
+    let mut data = vec![];
+    let mut offsets = vec![];
+    for record in old.chunks_exact(V1_RECORD_SIZE) {
+        let value = record
+            .get(1..1 + record[0] as usize)
+            .filter(|v| v.len() < V1_RECORD_SIZE - 4)
+            .ok_or(Error::Corrupted("old data file has a record too long"))?;
+        offsets.extend(u64::to_le_bytes(data.len() as u64));
+        offsets.extend(u32::to_le_bytes(value.len() as u32));
+        offsets.extend(u32::to_le_bytes(read_int(record, V1_RECORD_SIZE / 4 - 1)));
+        data.extend_from_slice(value);
+    }
+    write_atomic(root, &moved("__data"), &data)?;
+    write_atomic(root, &moved("__offsets"), &offsets)?;
+    write_atomic(
+        root,
+        &moved("__all"),
+        &v1_postings(&read_v1(root, "__all")?)?,
+    )?;
+
+    for name in v1_tag_files(root)? {
+        let bytes = read_v1(root, &name)?;
+        if bytes.is_empty() {
+            continue;
+        }
+        // fails before anything was moved or removed so no tag is lost
+        let tag = TagName(name);
+        let file = tag_file(&tag).ok_or_else(|| Error::InvalidTagName(tag.clone()))?;
+        save_name(root, &moved(&file), &tag.0)?;
+        write_atomic(root, &moved(&file), &v1_postings(&bytes)?)?;
+    }
+    write_atomic(root, &moved(DONE_FILE), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{temp_store, Store, Value};
+
+    /// Writes a store the way the original rtag did, values get IDs from 1
+    fn write_v1(root: &Path, values: &[&str], tags: &[(&str, &[u32])]) {
+        let mut data = vec![];
+        let mut all = vec![];
+        for (i, v) in values.iter().enumerate() {
+            let mut record = vec![0; V1_RECORD_SIZE];
+            record[0] = v.len() as u8;
+            record[1..1 + v.len()].copy_from_slice(v.as_bytes());
+            record[V1_RECORD_SIZE - 4..].copy_from_slice(&(i as u32 + 1).to_le_bytes());
+            data.extend(record);
+            all.extend((i as u32 + 1).to_le_bytes());
+        }
+        std::fs::write(root.join("__data"), data).unwrap();
+        std::fs::write(root.join("__all"), all).unwrap();
+        for (name, ids) in tags {
+            let bytes: Vec<u8> = ids.iter().flat_map(|x| x.to_le_bytes()).collect();
+            std::fs::write(root.join(name), bytes).unwrap();
+        }
+    }
+
+    fn values(store: &Store, qry: &str) -> Vec<String> {
+        let mut v: Vec<_> = store.query(qry, usize::MAX).unwrap();
+        v.sort_by(|a, b| a.0.cmp(&b.0));
+        v.into_iter().map(|x| x.0).collect()
+    }
+
+    #[test]
+    fn migrates_v1_store() {
+        let root = temp_store("migrate-v1");
+        // too long once its uppercase letters are escaped
+        let long = "L".repeat(200);
+        write_v1(
+            &root,
+            &["x", "y", "z"],
+            &[("tags", &[1, 2]), ("Foo", &[2]), (&long, &[1, 3])],
+        );
+        let store = Store::open(&root).unwrap();
+        assert!(matches!(store.query("tags", 10), Err(Error::OldFormat)));
+
+        assert_eq!(store.migrate().unwrap(), 1);
+        assert_eq!(store.migrate().unwrap(), FORMAT_VERSION);
+        assert_eq!(values(&store, "tags"), ["x", "y"]);
+        assert_eq!(values(&store, "\"Foo\""), ["y"]);
+        assert_eq!(values(&store, &format!("\"{}\"", long)), ["x", "z"]);
+        assert_eq!(values(&store, "*"), ["x", "y", "z"]);
+        let mut tags: Vec<_> = store.tags().unwrap().into_iter().map(|x| x.0).collect();
+        tags.sort();
+        assert_eq!(tags, ["Foo", &long, "tags"]);
+        let hashed = std::fs::read_dir(root.join(TAGS_DIR))
+            .unwrap()
+            .filter(|f| {
+                f.as_ref()
+                    .unwrap()
+                    .file_name()
+                    .to_str()
+                    .unwrap()
+                    .starts_with('~')
+            })
+            .count();
+        assert_eq!(hashed, 2, "the long tag and its name file");
+
+        store
+            .add_tag(&TagName("new".to_string()), &Value("z".to_string()))
+            .unwrap();
+        assert_eq!(values(&store, "new & tags | Foo"), ["y"]);
+        assert_eq!(values(&store, "new"), ["z"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn failed_migration_keeps_v1_store() {
+        let root = temp_store("migrate-v1-corrupt");
+        write_v1(&root, &["x", "y"], &[("a", &[1, 2])]);
+        std::fs::write(root.join("b"), [1, 0, 0]).unwrap();
+        let store = Store::open(&root).unwrap();
+
+        assert!(matches!(store.migrate(), Err(Error::Corrupted(_))));
+        assert_eq!(std::fs::read(root.join("a")).unwrap().len(), 8);
+        assert_eq!(std::fs::read(root.join("__data")).unwrap().len(), 512);
+        assert!(matches!(store.query("a", 10), Err(Error::OldFormat)));
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,35 @@
+/// Whether `s` matches the glob `pattern`,
+/// where `*` stands for any sequence of characters and `?` for a single character
+pub fn matches(pattern: &str, s: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+    let (mut pi, mut si) = (0, 0);
+    // position of the last `*` and the character of `s` it was matched up to
+    let mut star = None;
+    while si < s.len() {
+        match p.get(pi) {
+            Some('*') => {
+                star = Some((pi, si));
+                pi += 1;
+            }
+            Some(&c) if c == '?' || c == s[si] => {
+                pi += 1;
+                si += 1;
+            }
+            _ => match star {
+                Some((spi, ssi)) => {
+                    pi = spi + 1;
+                    si = ssi + 1;
+                    star = Some((spi, ssi + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// Whether the tag name in a query is a pattern rather than a plain tag
+pub fn is_glob(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
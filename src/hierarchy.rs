@@ -0,0 +1,56 @@
+use crate::error::Result;
+use crate::postings::PostingList;
+use crate::tagname::{list_tags, tag_file};
+use crate::write::open_tagmap;
+use crate::{TagName, ID};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+/// Tags form a hierarchy through `/`: a value tagged `music/rock/punk`
+/// also matches `music/rock` and `music`.
+/// Only the tags actually set are stored, parents are implied at query time.
+pub const SEPARATOR: char = '/';
+
+/// The tag itself followed by its parents, closest first: `a/b/c`, `a/b`, `a`
+pub fn ancestors(tag: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(tag).chain(
+        tag.rmatch_indices(SEPARATOR)
+            .map(move |(i, _)| &tag[..i])
+            .filter(|x| !x.is_empty()),
+    )
+}
+
+/// Whether `tag` is `parent` or one of its descendants
+pub fn is_under(tag: &str, parent: &str) -> bool {
+    tag.strip_prefix(parent)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with(SEPARATOR))
+}
+
+/// Every node of the hierarchy, implied parents included, sorted so that
+/// parents come right before their children, along with how many values are under each
+pub fn tree(root: &mut PathBuf) -> Result<Vec<(TagName, usize)>> {
+    let mut nodes: BTreeMap<String, BTreeSet<ID>> = BTreeMap::new();
+    for tag in list_tags(root)? {
+        let map = match tag_file(&tag) {
+            Some(name) => open_tagmap(root, &name)?,
+            None => None,
+        };
+        let ids: Vec<ID> = match &map {
+            Some(map) => PostingList::new(map)?.iter().collect(),
+            None => vec![],
+        };
+        for node in ancestors(&tag.0) {
+            nodes
+                .entry(node.to_string())
+                .or_default()
+                .extend(ids.iter().copied());
+        }
+    }
+    let mut nodes: Vec<_> = nodes
+        .into_iter()
+        .map(|(name, ids)| (TagName(name), ids.len()))
+        .collect();
+    // sorting by segments keeps `a/b` right after `a`, before `a-c`
+    nodes.sort_by(|a, b| a.0 .0.split(SEPARATOR).cmp(b.0 .0.split(SEPARATOR)));
+    Ok(nodes)
+}
@@ -0,0 +1,253 @@
+use crate::error::{io, Error, Result};
+use crate::qry::{read_int, write_int};
+use memmap2::{Mmap, MmapMut};
+use std::fs::File;
+use std::path::PathBuf;
+
+/// On-disk open addressing hash table mapping a value to its record in `__data`.
+///
+/// Layout: `[records indexed: u32][capacity: u32][slots: u32 * capacity]`.
+/// A slot holds the record number + 1, 0 means empty.
+/// The index is stale when the number of records indexed does not match `__data`,
+/// or when the capacity is not a power of two at least twice that number,
+/// in which case it is rebuilt from scratch.
+const HEADER_INTS: usize = 2;
+const MIN_CAPACITY: usize = 1024;
+
+pub struct Index {
+    file: File,
+    map: MmapMut,
+}
+
+/// FNV-1a, we need a hash that is stable across rust versions since it is persisted
+pub fn hash_value(v: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for &b in v {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+fn is_valid(map: &[u8], n_records: usize) -> bool {
+    if map.len() < HEADER_INTS * 4 {
+        return false;
+    }
+    let (len, capacity) = (read_int(map, 0) as usize, read_int(map, 1) as usize);
+    capacity.is_power_of_two()
+        && capacity >= MIN_CAPACITY
+        && capacity >= len * 2
+        && map.len() == (HEADER_INTS + capacity) * 4
+        && len == n_records
+}
+
+/// Finds the record number holding exactly `needle` in a valid index
+pub fn lookup<'a>(
+    map: &[u8],
+    needle: &[u8],
+    record: impl Fn(usize) -> Result<&'a [u8]>,
+) -> Result<Option<usize>> {
+    let capacity = read_int(map, 1) as usize;
+    let mask = capacity - 1;
+    let mut slot = hash_value(needle) as usize & mask;
+    for _ in 0..capacity {
+        let v = read_int(map, HEADER_INTS + slot) as usize;
+        if v == 0 {
+            return Ok(None);
+        }
+        if record(v - 1)? == needle {
+            return Ok(Some(v - 1));
+        }
+        slot = (slot + 1) & mask;
+    }
+    Err(Error::Corrupted("index has no empty slot"))
+}
+
+impl Index {
+    /// Opens the index, rebuilding it if it is missing or does not cover every record.
+    /// `record` gives the bytes of the nth record.
+    pub fn open<'a>(
+        root: &mut PathBuf,
+        n_records: usize,
+        record: impl Fn(usize) -> Result<&'a [u8]>,
+    ) -> Result<Index> {
+        root.push("__index");
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .read(true)
+            .open(&root);
+        root.pop();
+        let file = file.map_err(io("could not open index file"))?;
+
+        let map = unsafe { MmapMut::map_mut(&file).map_err(io("could not memmap index file"))? };
+        let mut index = Index { file, map };
+        if !is_valid(&index.map, n_records) {
+            index.rebuild(n_records, record)?;
+        }
+        Ok(index)
+    }
+
+    /// Opens the index without writing to it, None if it is missing or stale
+    pub fn open_read(root: &mut PathBuf, n_records: usize) -> Result<Option<Mmap>> {
+        root.push("__index");
+        let file = File::open(&root);
+        root.pop();
+        let file = match file {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(Error::Io {
+                    context: "could not open index file",
+                    source: e,
+                })
+            }
+        };
+        let map = unsafe { Mmap::map(&file).map_err(io("could not memmap index file"))? };
+        Ok(is_valid(&map, n_records).then_some(map))
+    }
+
+    fn capacity(&self) -> usize {
+        read_int(&self.map, 1) as usize
+    }
+
+    fn rebuild<'a>(
+        &mut self,
+        n_records: usize,
+        record: impl Fn(usize) -> Result<&'a [u8]>,
+    ) -> Result<()> {
+        let capacity = (n_records * 2 + 1).next_power_of_two().max(MIN_CAPACITY);
+        self.file
+            .set_len(0)
+            .map_err(io("could not truncate index file"))?;
+        self.file
+            .set_len(((HEADER_INTS + capacity) * 4) as u64)
+            .map_err(io("could not resize index file"))?;
+        self.map =
+            unsafe { MmapMut::map_mut(&self.file).map_err(io("could not memmap index file"))? };
+
+        write_int(&mut self.map, 1, capacity as u32);
+        for i in 0..n_records {
+            self.insert_slot(hash_value(record(i)?), i)?;
+        }
+        write_int(&mut self.map, 0, n_records as u32);
+        Ok(())
+    }
+
+    /// Finds the record number holding exactly `needle`
+    pub fn lookup<'a>(
+        &self,
+        needle: &[u8],
+        record: impl Fn(usize) -> Result<&'a [u8]>,
+    ) -> Result<Option<usize>> {
+        lookup(&self.map, needle, record)
+    }
+
+    /// Registers a freshly appended record, `n` must be the number of records before the append
+    pub fn insert<'a>(
+        &mut self,
+        value: &[u8],
+        n: usize,
+        record: impl Fn(usize) -> Result<&'a [u8]>,
+    ) -> Result<()> {
+        if (n + 1) * 2 > self.capacity() {
+            self.rebuild(n, record)?;
+        }
+        self.insert_slot(hash_value(value), n)?;
+        write_int(&mut self.map, 0, n as u32 + 1);
+        Ok(())
+    }
+
+    fn insert_slot(&mut self, hash: u64, recordn: usize) -> Result<()> {
+        let mask = self.capacity() - 1;
+        let mut slot = hash as usize & mask;
+        for _ in 0..self.capacity() {
+            if read_int(&self.map, HEADER_INTS + slot) == 0 {
+                write_int(&mut self.map, HEADER_INTS + slot, recordn as u32 + 1);
+                return Ok(());
+            }
+            slot = (slot + 1) & mask;
+        }
+        Err(Error::Corrupted("index has no empty slot"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::temp_store;
+
+    fn values(n: usize) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|i| format!("value {}", i).into_bytes())
+            .collect()
+    }
+
+    fn assert_finds(index: &Index, values: &[Vec<u8>]) {
+        let record = |i: usize| Ok(&values[i][..]);
+        for (i, v) in values.iter().enumerate() {
+            assert_eq!(index.lookup(v, record).unwrap(), Some(i));
+        }
+        assert_eq!(index.lookup(b"missing", record).unwrap(), None);
+    }
+
+    #[test]
+    fn rebuilds_stale_index() {
+        let mut root = temp_store("stale-index");
+        let values = values(3000);
+        let record = |i: usize| Ok(&values[i][..]);
+
+        let index = Index::open(&mut root, 10, record).unwrap();
+        assert_finds(&index, &values[..10]);
+        drop(index);
+
+        // records appended without updating the index
+        assert!(Index::open_read(&mut root, 3000).unwrap().is_none());
+        let index = Index::open(&mut root, 3000, record).unwrap();
+        assert_finds(&index, &values);
+        drop(index);
+        assert!(Index::open_read(&mut root, 3000).unwrap().is_some());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rebuilds_corrupt_index() {
+        let mut root = temp_store("corrupt-index");
+        let values = values(100);
+        let record = |i: usize| Ok(&values[i][..]);
+
+        let header = |len: u32, capacity: u32, slots: usize| {
+            let mut bytes = vec![];
+            bytes.extend(len.to_le_bytes());
+            bytes.extend(capacity.to_le_bytes());
+            bytes.resize((HEADER_INTS + slots) * 4, 0);
+            bytes
+        };
+        for bytes in [
+            vec![1, 2, 3],
+            header(100, 0, 0),
+            header(100, 1000, 1000),
+            header(100, 1024, 1000),
+            header(100, 128, 128),
+        ] {
+            std::fs::write(root.join("__index"), bytes).unwrap();
+            assert!(Index::open_read(&mut root, 100).unwrap().is_none());
+            let index = Index::open(&mut root, 100, record).unwrap();
+            assert_finds(&index, &values);
+        }
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn full_index_is_corrupted() {
+        let values = values(2);
+        let mut map = vec![0; (HEADER_INTS + MIN_CAPACITY) * 4];
+        write_int(&mut map, 1, MIN_CAPACITY as u32);
+        for slot in 0..MIN_CAPACITY {
+            write_int(&mut map, HEADER_INTS + slot, 1);
+        }
+        let found = lookup(&map, b"missing", |i: usize| Ok(&values[i][..]));
+        assert!(matches!(found, Err(Error::Corrupted(_))));
+    }
+}
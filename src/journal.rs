@@ -0,0 +1,67 @@
+use crate::error::{io, Error, Result};
+use crate::qry::read_int;
+use crate::write::write_atomic;
+use crate::{TagName, Value};
+use std::path::{Path, PathBuf};
+
+/// The journal records the operation in progress so it can be replayed if rtag dies midway.
+/// Every operation is idempotent so replaying one that had already completed is harmless.
+///
+/// Layout: `[op: u8][tag len: u32][tag][value len: u32][value]`
+const OP_ADD: u8 = b'A';
+const OP_DEL: u8 = b'D';
+
+pub enum Op {
+    AddTag(TagName, Value),
+    DelTag(TagName, Value),
+}
+
+pub fn begin(root: &Path, op: &Op) -> Result<()> {
+    let (code, tag, value) = match op {
+        Op::AddTag(tag, value) => (OP_ADD, tag, value),
+        Op::DelTag(tag, value) => (OP_DEL, tag, value),
+    };
+    let mut bytes = Vec::with_capacity(9 + tag.0.len() + value.0.len());
+    bytes.push(code);
+    bytes.extend(u32::to_le_bytes(tag.0.len() as u32));
+    bytes.extend(tag.0.bytes());
+    bytes.extend(u32::to_le_bytes(value.0.len() as u32));
+    bytes.extend(value.0.bytes());
+    write_atomic(root, "__journal", &bytes)
+}
+
+pub fn commit(root: &mut PathBuf) -> Result<()> {
+    root.push("__journal");
+    let res = std::fs::remove_file(&root);
+    root.pop();
+    res.map_err(io("could not remove journal"))
+}
+
+/// Returns the operation that was interrupted, if any
+pub fn pending(root: &mut PathBuf) -> Result<Option<Op>> {
+    root.push("__journal");
+    let bytes = std::fs::read(&root);
+    root.pop();
+    let bytes = match bytes {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+
+    fn string(bytes: &[u8], pos: &mut usize) -> Result<String> {
+        let corrupted = || Error::Corrupted("journal is truncated");
+        let len = read_int(bytes.get(*pos..*pos + 4).ok_or_else(corrupted)?, 0) as usize;
+        *pos += 4;
+        let s = bytes.get(*pos..*pos + len).ok_or_else(corrupted)?;
+        *pos += len;
+        String::from_utf8(s.to_vec()).map_err(|_| Error::Corrupted("journal is not valid utf-8"))
+    }
+
+    let mut pos = 1;
+    let tag = TagName(string(&bytes, &mut pos)?);
+    let value = Value(string(&bytes, &mut pos)?);
+    match bytes[0] {
+        OP_ADD => Ok(Some(Op::AddTag(tag, value))),
+        OP_DEL => Ok(Some(Op::DelTag(tag, value))),
+        _ => Err(Error::Corrupted("unknown journal operation")),
+    }
+}
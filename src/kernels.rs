@@ -0,0 +1,215 @@
+//! Intersection and difference of sorted, deduplicated lists of IDs.
+//!
+//! Three kernels, picked by [`intersect`] and [`difference`] from the size ratio of the lists:
+//! - merge: walks both lists in step, O(n + m)
+//! - gallop: looks every ID of the small list up in the large one with exponential search,
+//!   O(n log(m / n)), for lists of very different sizes
+//! - blocks: a merge comparing four IDs against four at once with SSE2, for lists of similar sizes
+use crate::ID;
+use std::borrow::Cow;
+
+/// Lists at least this many times larger than the other one are galloped through
+pub const GALLOP_RATIO: usize = 32;
+
+/// The IDs of an array encoded tag file, borrowed from the map when aligned
+pub fn ids(bytes: &[u8]) -> Cow<'_, [u32]> {
+    // SAFETY: any 4 bytes make a valid u32
+    let (head, ids, tail) = unsafe { bytes.align_to::<u32>() };
+    if cfg!(target_endian = "little") && head.is_empty() && tail.is_empty() {
+        return Cow::Borrowed(ids);
+    }
+    Cow::Owned(
+        bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(),
+    )
+}
+
+pub(crate) fn as_u32(ids: &[ID]) -> &[u32] {
+    // SAFETY: ID is a transparent u32
+    unsafe { std::slice::from_raw_parts(ids.as_ptr() as *const u32, ids.len()) }
+}
+
+pub(crate) fn into_ids(ids: Vec<u32>) -> Vec<ID> {
+    let mut ids = std::mem::ManuallyDrop::new(ids);
+    // SAFETY: ID is a transparent u32, the allocation is handed over as is
+    unsafe { Vec::from_raw_parts(ids.as_mut_ptr() as *mut ID, ids.len(), ids.capacity()) }
+}
+
+/// Pushes the IDs in both `a` and `b`
+pub fn intersect(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if large.len() / small.len().max(1) >= GALLOP_RATIO {
+        intersect_gallop(small, large, out);
+    } else {
+        intersect_blocks(a, b, out);
+    }
+}
+
+/// Pushes the IDs of `a` that are not in `b`
+pub fn difference(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let ratio = |x: &[u32], y: &[u32]| x.len() / y.len().max(1);
+    if ratio(a, b) >= GALLOP_RATIO || ratio(b, a) >= GALLOP_RATIO {
+        difference_gallop(a, b, out);
+    } else {
+        difference_blocks(a, b, out);
+    }
+}
+
+/// First position at or after `pos` holding at least `x`
+fn gallop(s: &[u32], pos: usize, x: u32) -> usize {
+    if pos >= s.len() || s[pos] >= x {
+        return pos;
+    }
+    // s[lo] < x
+    let mut lo = pos;
+    let mut step = 1;
+    while lo + step < s.len() && s[lo + step] < x {
+        lo += step;
+        step *= 2;
+    }
+    let end = (lo + step + 1).min(s.len());
+    lo + 1 + s[lo + 1..end].partition_point(|&v| v < x)
+}
+
+pub fn intersect_merge(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        let (x, y) = (a[i], b[j]);
+        if x == y {
+            out.push(x);
+        }
+        i += (x <= y) as usize;
+        j += (y <= x) as usize;
+    }
+}
+
+pub fn difference_merge(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let mut j = 0;
+    for &x in a {
+        while j < b.len() && b[j] < x {
+            j += 1;
+        }
+        if j >= b.len() || b[j] != x {
+            out.push(x);
+        }
+    }
+}
+
+pub fn intersect_gallop(small: &[u32], large: &[u32], out: &mut Vec<u32>) {
+    let mut pos = 0;
+    for &x in small {
+        pos = gallop(large, pos, x);
+        if pos >= large.len() {
+            break;
+        }
+        if large[pos] == x {
+            out.push(x);
+        }
+    }
+}
+
+pub fn difference_gallop(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    if a.len() <= b.len() {
+        // look every ID of a up in b
+        let mut pos = 0;
+        for &x in a {
+            pos = gallop(b, pos, x);
+            if pos >= b.len() || b[pos] != x {
+                out.push(x);
+            }
+        }
+        return;
+    }
+    // copy the runs of a between the IDs of b
+    let mut start = 0;
+    for &y in b {
+        let pos = gallop(a, start, y);
+        out.extend_from_slice(&a[start..pos]);
+        start = pos + (pos < a.len() && a[pos] == y) as usize;
+        if start >= a.len() {
+            return;
+        }
+    }
+    out.extend_from_slice(&a[start..]);
+}
+
+pub fn intersect_blocks(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let (i, j, mask) = blocks(a, b, true, out);
+    tail(&a[i..], &b[j..], mask, true, out);
+}
+
+pub fn difference_blocks(a: &[u32], b: &[u32], out: &mut Vec<u32>) {
+    let (i, j, mask) = blocks(a, b, false, out);
+    tail(&a[i..], &b[j..], mask, false, out);
+}
+
+/// Bit k is set if `a[k]` is one of `b[..4]`
+#[cfg(target_arch = "x86_64")]
+fn block_mask(a: &[u32], b: &[u32]) -> u32 {
+    use std::arch::x86_64::*;
+    assert!(a.len() >= 4 && b.len() >= 4);
+    // SAFETY: sse2 is always available on x86_64 and both blocks are in bounds
+    unsafe {
+        let va = _mm_loadu_si128(a.as_ptr() as *const __m128i);
+        let vb = _mm_loadu_si128(b.as_ptr() as *const __m128i);
+        let rot1 = _mm_shuffle_epi32::<0b00_11_10_01>(vb);
+        let rot2 = _mm_shuffle_epi32::<0b01_00_11_10>(vb);
+        let rot3 = _mm_shuffle_epi32::<0b10_01_00_11>(vb);
+        let eq = _mm_or_si128(
+            _mm_or_si128(_mm_cmpeq_epi32(va, vb), _mm_cmpeq_epi32(va, rot1)),
+            _mm_or_si128(_mm_cmpeq_epi32(va, rot2), _mm_cmpeq_epi32(va, rot3)),
+        );
+        _mm_movemask_ps(_mm_castsi128_ps(eq)) as u32
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn block_mask(a: &[u32], b: &[u32]) -> u32 {
+    let mut mask = 0;
+    for k in 0..4 {
+        for &y in &b[..4] {
+            mask |= ((a[k] == y) as u32) << k;
+        }
+    }
+    mask
+}
+
+/// Compares `a` and `b` four IDs at a time, pushing the IDs of `a` found in `b` if `found`,
+/// or the ones missing from it otherwise, for every block of `a` it gets past.
+/// Returns where both lists stop and which IDs of the current block of `a` were found so far.
+fn blocks(a: &[u32], b: &[u32], found: bool, out: &mut Vec<u32>) -> (usize, usize, u32) {
+    let (mut i, mut j, mut mask) = (0, 0, 0);
+    while i + 4 <= a.len() && j + 4 <= b.len() {
+        mask |= block_mask(&a[i..], &b[j..]);
+        let (amax, bmax) = (a[i + 3], b[j + 3]);
+        if amax <= bmax {
+            for k in 0..4 {
+                if (mask >> k & 1 == 1) == found {
+                    out.push(a[i + k]);
+                }
+            }
+            i += 4;
+            mask = 0;
+        }
+        if bmax <= amax {
+            j += 4;
+        }
+    }
+    (i, j, mask)
+}
+
+/// Merges what is left once a list has less than a block, `mask` holds the IDs of `a` already found
+fn tail(a: &[u32], b: &[u32], mask: u32, found: bool, out: &mut Vec<u32>) {
+    let mut j = 0;
+    for (k, &x) in a.iter().enumerate() {
+        while j < b.len() && b[j] < x {
+            j += 1;
+        }
+        let hit = (k < 4 && mask >> k & 1 == 1) || (j < b.len() && b[j] == x);
+        if hit == found {
+            out.push(x);
+        }
+    }
+}
@@ -0,0 +1,52 @@
+mod dnf;
+mod error;
+mod eval;
+mod explain;
+mod facets;
+mod format;
+mod glob;
+mod hierarchy;
+mod index;
+mod journal;
+pub mod kernels;
+mod lock;
+mod page;
+mod parse;
+mod plan;
+mod postings;
+mod qry;
+mod reverse;
+mod saved;
+mod store;
+mod tagname;
+mod write;
+
+pub use error::{Error, Result};
+pub use explain::Explain;
+pub use format::FORMAT_VERSION;
+pub use lock::{LockKind, LockTimeout};
+pub use page::{Cursor, Order, Page};
+pub use parse::ParseError;
+pub use plan::{AndPlan, AndStats, Conjunct, QueryPlan, Strategy};
+pub use qry::{Atom, Expr, ValueMatch, DNF};
+pub use store::Store;
+pub use write::STORE_DIR;
+
+#[derive(Copy, Clone, Debug, Ord, PartialOrd, Eq, PartialEq)]
+#[repr(transparent)]
+pub struct ID(u32);
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Value(pub String);
+
+#[derive(PartialOrd, Ord, Clone, Debug, Eq, PartialEq)]
+pub struct TagName(pub String);
+
+/// Empty directory for a test to put a store in
+#[cfg(test)]
+fn temp_store(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("rtag-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,115 @@
+use crate::error::{io, Error, Result};
+use crate::format;
+use crate::write::{needs_recovery, recover};
+use fs2::FileExt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum LockKind {
+    /// Many readers can hold the store at once
+    Shared,
+    /// A single writer holds the store
+    Exclusive,
+}
+
+/// Advisory lock on the store, released when dropped
+pub struct StoreLock {
+    _file: File,
+    /// Why the journal of an interrupted write was discarded while locking, if it was
+    pub(crate) discarded_journal: Option<Error>,
+}
+
+#[derive(Debug)]
+pub struct LockTimeout {
+    pub kind: LockKind,
+    pub waited: Duration,
+}
+
+impl Display for LockTimeout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let kind = match self.kind {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        };
+        write!(
+            f,
+            "could not acquire {} lock on the store after {:.1}s: another rtag process is using it",
+            kind,
+            self.waited.as_secs_f32()
+        )
+    }
+}
+
+fn acquire(file: &File, kind: LockKind, timeout: Duration) -> std::result::Result<(), LockTimeout> {
+    let start = Instant::now();
+    loop {
+        let res = match kind {
+            LockKind::Shared => FileExt::try_lock_shared(file),
+            LockKind::Exclusive => FileExt::try_lock_exclusive(file),
+        };
+        if res.is_ok() {
+            return Ok(());
+        }
+        let waited = start.elapsed();
+        if waited >= timeout {
+            return Err(LockTimeout { kind, waited });
+        }
+        std::thread::sleep(POLL_INTERVAL.min(timeout - waited));
+    }
+}
+
+fn open_lock_file(root: &mut PathBuf) -> Result<File> {
+    root.push("__lock");
+    let file = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .read(true)
+        .open(&root);
+    root.pop();
+    file.map_err(io("could not open lock file"))
+}
+
+/// Locks the store, waiting at most `timeout` for other processes to release it.
+/// Fails if the store has another layout than this version of rtag's, before touching it.
+/// Recovers from an interrupted write before returning, which briefly requires an exclusive lock.
+pub fn lock(root: &mut PathBuf, kind: LockKind, timeout: Duration) -> Result<StoreLock> {
+    let file = open_lock_file(root)?;
+
+    acquire(&file, kind, timeout)?;
+    format::check(root, kind)?;
+    let mut discarded_journal = None;
+    if needs_recovery(root) {
+        if kind == LockKind::Shared {
+            acquire(&file, LockKind::Exclusive, timeout)?;
+        }
+        // another process might have recovered while we were upgrading
+        if needs_recovery(root) {
+            discarded_journal = recover(root)?;
+        }
+        if kind == LockKind::Shared {
+            acquire(&file, LockKind::Shared, timeout)?;
+        }
+    }
+
+    Ok(StoreLock {
+        _file: file,
+        discarded_journal,
+    })
+}
+
+/// Locks the store exclusively without checking its layout nor recovering,
+/// for the operations that work on any layout such as migrating it
+pub fn lock_any_format(root: &mut PathBuf, timeout: Duration) -> Result<StoreLock> {
+    let file = open_lock_file(root)?;
+    acquire(&file, LockKind::Exclusive, timeout)?;
+    Ok(StoreLock {
+        _file: file,
+        discarded_journal: None,
+    })
+}
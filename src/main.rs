@@ -1,21 +1,11 @@
-mod dnf;
-mod parse;
-mod qry;
-mod write;
-
-use crate::write::{add_tag, del_tag, getroot};
-use clap::{Parser, Subcommand};
+use clap::{ArgEnum, Parser, Subcommand};
+use rtag::{
+    Cursor, Error, Order, Page, ParseError, Store, TagName, Value, FORMAT_VERSION, STORE_DIR,
+};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-
-#[derive(Copy, Clone, Ord, PartialOrd, Eq, PartialEq)]
-#[repr(transparent)]
-pub struct ID(u32);
-
-pub struct Value(String);
-
-#[derive(PartialOrd, Ord, Clone, Debug, Eq, PartialEq)]
-pub struct TagName(String);
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[clap(name = "rtag")]
@@ -24,6 +14,12 @@ pub struct TagName(String);
 #[clap(about = "General Tagging CLI", long_about = None)]
 #[clap(propagate_version = true)]
 struct Cli {
+    /// Path of the store, defaults to $RTAG_HOME or ~/.rtag
+    #[clap(long, global = true)]
+    store: Option<PathBuf>,
+    /// Seconds to wait for other rtag processes to release the store
+    #[clap(long, global = true, default_value_t = 10.0)]
+    lock_timeout: f64,
     #[clap(subcommand)]
     command: Commands,
 }
@@ -35,9 +31,44 @@ enum Commands {
         /// Number of matches to fetch at most
         #[clap(short, long, default_value_t = 100)]
         limit: usize,
+        /// Number of matches to skip
+        #[clap(long, default_value_t = 0)]
+        offset: usize,
+        /// Resume after the cursor printed at the end of the previous page
+        #[clap(long)]
+        after: Option<Cursor>,
+        /// Order of the matches
+        #[clap(long, arg_enum, default_value = "id")]
+        sort: Sort,
+        /// Return the matches in reverse order
+        #[clap(long)]
+        reverse: bool,
+        /// Print how the query was run instead of the values
+        #[clap(long)]
+        explain: bool,
+        /// Print the number of matches instead of the values
+        #[clap(long, conflicts_with = "explain")]
+        count: bool,
         /// Multiple queries are ANDed together
         qry: Vec<String>,
     },
+    /// Counts the tags found among the values matching a query
+    Facets {
+        /// Number of tags to print at most
+        #[clap(short, long, default_value_t = 20)]
+        limit: usize,
+        /// Only count the tags starting with this, such as `artist/`
+        #[clap(long)]
+        prefix: Option<String>,
+        qry: Vec<String>,
+    },
+    /// Saves a query so other queries can reference it as @name
+    Save { name: String, qry: Vec<String> },
+    /// Lists the saved queries, or edits or deletes one
+    Saved {
+        #[clap(subcommand)]
+        action: Option<SavedAction>,
+    },
     /// Sets tag to values
     Set { tag: String, values: Vec<String> },
     /// Remove tag from values
@@ -48,54 +79,222 @@ enum Commands {
     GenTestData { dataset: Option<u32> },
     /// List all tags
     Tags {},
+    /// Print the tags of each value
+    Show { values: Vec<String> },
+    /// Print the tag hierarchy with the number of values under each tag
+    Tree {},
+    /// Create a project-local store in the given directory, defaults to the current one
+    Init { dir: Option<PathBuf> },
+    /// Upgrade a store created by an older rtag
+    Migrate {},
+}
+
+#[derive(Subcommand)]
+enum SavedAction {
+    /// Replaces the query of an existing saved query
+    Edit { name: String, qry: Vec<String> },
+    /// Deletes a saved query
+    #[clap(alias = "rm")]
+    Delete { name: String },
+}
+
+#[derive(ArgEnum, Clone, Copy)]
+enum Sort {
+    Id,
+    Value,
+    Insertion,
+}
+
+impl From<Sort> for Order {
+    fn from(sort: Sort) -> Order {
+        match sort {
+            Sort::Id => Order::Id,
+            Sort::Value => Order::Value,
+            Sort::Insertion => Order::Insertion,
+        }
+    }
 }
 
 fn cli() -> Cli {
     Cli::parse()
 }
 
-fn main() {
-    let cli = cli();
+fn init(dir: Option<PathBuf>) -> rtag::Result<()> {
+    let dir = dir.unwrap_or_else(|| PathBuf::from("."));
+    if dir.join(STORE_DIR).is_dir() {
+        println!("store already exists at {}", dir.join(STORE_DIR).display());
+        return Ok(());
+    }
+    let store = Store::init(dir)?;
+    println!("initialized empty store at {}", store.root().display());
+    Ok(())
+}
+
+/// Shows the query with a caret under the offending spot
+fn print_parse_error(qry: &str, e: &ParseError) {
+    let col = qry[..e.offset].chars().count();
+    eprintln!("error: invalid query: {}", e);
+    eprintln!("  {}", qry);
+    eprintln!("  {}^", " ".repeat(col));
+}
+
+fn no_saved_query(name: &str) -> ! {
+    eprintln!("error: no saved query named {:?}", name);
+    std::process::exit(1);
+}
+
+/// Exits showing where the query is wrong if it could not be parsed
+fn parsed<T>(qry: &str, res: rtag::Result<T>) -> rtag::Result<T> {
+    if let Err(Error::Parse(e)) = &res {
+        print_parse_error(qry, e);
+        std::process::exit(1);
+    }
+    res
+}
+
+fn run(cli: Cli) -> rtag::Result<()> {
+    if let Commands::Init { dir } = cli.command {
+        return init(dir);
+    }
+
+    let store = match cli.store {
+        Some(path) => Store::open(path)?,
+        None => Store::discover()?,
+    };
+    let store = store.with_lock_timeout(Duration::from_secs_f64(cli.lock_timeout.max(0.0)));
+    if let Commands::Clean {} = cli.command {
+        return store.clean();
+    }
 
     match cli.command {
-        Commands::Qry { mut limit, qry } => {
+        Commands::Qry {
+            mut limit,
+            offset,
+            after,
+            sort,
+            reverse,
+            explain,
+            count,
+            qry,
+        } => {
             if limit == 0 {
                 limit = usize::MAX;
             }
-            for val in qry::parse_and_execute(&qry.join(" "), limit) {
+            let page = Page {
+                order: sort.into(),
+                reverse,
+                offset,
+                after,
+                limit,
+            };
+            let qry = qry.join(" ");
+            if count {
+                println!("{}", parsed(&qry, store.count(&qry))?);
+                return Ok(());
+            }
+            let (values, report) = parsed(&qry, store.explain(&qry, &page))?;
+            if explain {
+                print!("{}", report);
+                return Ok(());
+            }
+            let full = values.len() == limit;
+            for val in values {
                 println!("{}", val.0);
             }
+            if let (true, Some(next)) = (full, report.next) {
+                eprintln!("next page: --after {}", next);
+            }
+        }
+        Commands::Facets {
+            mut limit,
+            prefix,
+            qry,
+        } => {
+            if limit == 0 {
+                limit = usize::MAX;
+            }
+            let qry = qry.join(" ");
+            for (tag, count) in parsed(&qry, store.facets(&qry, prefix.as_deref(), limit))? {
+                println!("{} ({})", tag.0, count);
+            }
+        }
+        Commands::Save { name, qry } => {
+            let qry = qry.join(" ");
+            parsed(&qry, store.save_query(&name, &qry))?;
         }
+        Commands::Saved { action } => match action {
+            None => {
+                for (name, qry) in store.saved_queries()? {
+                    println!("@{} = {}", name, qry);
+                }
+            }
+            Some(SavedAction::Edit { name, qry }) => {
+                if store.saved_query(&name)?.is_none() {
+                    no_saved_query(&name);
+                }
+                let qry = qry.join(" ");
+                parsed(&qry, store.save_query(&name, &qry))?;
+            }
+            Some(SavedAction::Delete { name }) => {
+                if !store.delete_saved_query(&name)? {
+                    no_saved_query(&name);
+                }
+            }
+        },
         Commands::Set { tag, values } => {
             let tag = TagName(tag);
             for val in values {
-                add_tag(&tag, &Value(val));
+                store.add_tag(&tag, &Value(val))?;
             }
         }
         Commands::Del { tag, values } => {
             let tag = TagName(tag);
             for val in values {
-                del_tag(&tag, &Value(val));
+                store.del_tag(&tag, &Value(val))?;
             }
         }
-        Commands::Clean {} => {
-            let root = getroot();
-            std::fs::remove_dir_all(root).expect("failed cleaning");
-        }
+        Commands::Clean {} => unreachable!("clean consumes the store"),
         Commands::Tags {} => {
-            let root = getroot();
-            for file in std::fs::read_dir(&root)
-                .expect("cannot read root")
-                .flat_map(|x| x.ok())
-            {
-                let fname = file.file_name();
-                let name = fname.to_string_lossy();
-                if name == "__all" || name == "__data" {
-                    continue;
+            for tag in store.tags()? {
+                println!("{}", tag.0);
+            }
+        }
+        Commands::Show { values } => {
+            let mut missing = false;
+            for val in values {
+                match store.value_tags(&Value(val.clone()))? {
+                    Some(tags) => {
+                        println!("{}", val);
+                        for tag in tags {
+                            println!("  {}", tag.0);
+                        }
+                    }
+                    None => {
+                        eprintln!("error: no value {:?} in the store", val);
+                        missing = true;
+                    }
                 }
-                println!("{}", name);
+            }
+            if missing {
+                warn_discarded_journal(&store);
+                std::process::exit(1);
+            }
+        }
+        Commands::Tree {} => {
+            for (tag, count) in store.tree()? {
+                let depth = tag.0.matches('/').count();
+                let name = tag.0.rsplit('/').next().unwrap_or(&tag.0);
+                println!("{}{} ({})", "  ".repeat(depth), name, count);
             }
         }
+        Commands::Migrate {} => match store.migrate()? {
+            FORMAT_VERSION => println!("store is up to date"),
+            from => println!(
+                "migrated store from format version {} to {}",
+                from, FORMAT_VERSION
+            ),
+        },
+        Commands::Init { .. } => unreachable!("init does not need a store"),
         Commands::GenTestData { dataset } => match dataset {
             Some(2) => {
                 println!("generating dataset: 50k items  10 tags  [1-10] items per tag");
@@ -111,7 +310,7 @@ fn main() {
                         let artiste = st.finish() % 10;
 
                         let artag = format!("a_{}", artiste);
-                        add_tag(&TagName(artag), &mustag);
+                        store.add_tag(&TagName(artag), &mustag)?;
                     }
                 }
             }
@@ -124,9 +323,24 @@ fn main() {
 
                     let mustag = format!("m_{}", musique);
                     let artag = format!("a_{}", artiste);
-                    add_tag(&TagName(artag), &Value(mustag));
+                    store.add_tag(&TagName(artag), &Value(mustag))?;
                 }
             }
         },
     }
+    warn_discarded_journal(&store);
+    Ok(())
+}
+
+fn warn_discarded_journal(store: &Store) {
+    if let Some(e) = store.take_discarded_journal() {
+        eprintln!("warning: discarded an interrupted write: {}", e);
+    }
+}
+
+fn main() {
+    if let Err(e) = run(cli()) {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    }
 }
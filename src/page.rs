@@ -0,0 +1,154 @@
+use crate::error::{Error, Result};
+use crate::qry::TagCtx;
+use crate::write::data;
+use crate::{Value, ID};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fmt::{Display, Formatter};
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+/// Order of the query results
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Order {
+    /// By internal ID, the cheapest since every set is kept in that order
+    #[default]
+    Id,
+    /// By value, every match has to be read to sort them
+    Value,
+    /// In the order values were first tagged, which IDs are handed out in
+    Insertion,
+}
+
+/// Where a page of results ends, to resume right after it
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cursor(pub(crate) ID);
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0 .0)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(Cursor(ID(s.parse()?)))
+    }
+}
+
+/// Which slice of the ordered results a query returns
+#[derive(Clone, Debug)]
+pub struct Page {
+    pub order: Order,
+    pub reverse: bool,
+    /// Number of results skipped
+    pub offset: usize,
+    /// Resumes after the value this cursor was returned with
+    pub after: Option<Cursor>,
+    pub limit: usize,
+}
+
+impl Page {
+    /// The first `limit` results by ID
+    pub fn new(limit: usize) -> Page {
+        Page {
+            order: Order::Id,
+            reverse: false,
+            offset: 0,
+            after: None,
+            limit,
+        }
+    }
+
+    /// Results are wanted in increasing ID order: only the IDs past the cursor matter,
+    /// and the page is within the first `offset + limit` of them
+    pub(crate) fn by_increasing_id(&self) -> Option<(usize, Option<ID>)> {
+        match self.order {
+            Order::Id | Order::Insertion if !self.reverse => Some((
+                self.offset.saturating_add(self.limit),
+                self.after.map(|c| c.0),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps the `k` smallest keys of the values, sorted, `key` returns None for values to skip
+fn smallest<T: Ord>(
+    ctx: &TagCtx,
+    ids: Vec<ID>,
+    k: usize,
+    key: impl Fn(String, ID) -> Option<T>,
+) -> Result<Vec<T>> {
+    let mut heap = BinaryHeap::new();
+    for id in ids {
+        let Some(x) = data(&ctx.offsetmap, &ctx.datamap, id)?.and_then(|v| key(v.0, id)) else {
+            continue;
+        };
+        if heap.len() < k {
+            heap.push(x);
+        } else if heap.peek().is_some_and(|top| x < *top) {
+            heap.pop();
+            heap.push(x);
+        }
+    }
+    Ok(heap.into_sorted_vec())
+}
+
+/// Picks the page out of the matching IDs, given in increasing order, along with their values
+pub(crate) fn select(ctx: &TagCtx, ids: Vec<ID>, page: &Page) -> Result<Vec<(ID, Value)>> {
+    let fetch = |id| data(&ctx.offsetmap, &ctx.datamap, id);
+    let k = page.offset.saturating_add(page.limit);
+
+    let ids: Vec<ID> = match page.order {
+        Order::Id | Order::Insertion => {
+            let after = page.after.map(|c| c.0);
+            let ids: Box<dyn Iterator<Item = ID>> = if page.reverse {
+                Box::new(
+                    ids.into_iter()
+                        .rev()
+                        .filter(|&id| after.is_none_or(|a| id < a)),
+                )
+            } else {
+                Box::new(ids.into_iter().filter(|&id| after.is_none_or(|a| id > a)))
+            };
+            ids.skip(page.offset).take(page.limit).collect()
+        }
+        Order::Value => {
+            let after = match page.after {
+                Some(cursor) => Some(fetch(cursor.0)?.ok_or(Error::InvalidCursor(cursor))?.0),
+                None => None,
+            };
+            let sorted: Vec<(String, ID)> = if page.reverse {
+                smallest(ctx, ids, k, |v, id| {
+                    after
+                        .as_ref()
+                        .is_none_or(|a| &v < a)
+                        .then_some(Reverse((v, id)))
+                })?
+                .into_iter()
+                .map(|x| x.0)
+                .collect()
+            } else {
+                smallest(ctx, ids, k, |v, id| {
+                    after.as_ref().is_none_or(|a| &v > a).then_some((v, id))
+                })?
+            };
+            return Ok(sorted
+                .into_iter()
+                .skip(page.offset)
+                .map(|(v, id)| (id, Value(v)))
+                .collect());
+        }
+    };
+
+    let mut values = Vec::with_capacity(ids.len());
+    for id in ids {
+        if let Some(v) = fetch(id)? {
+            values.push((id, v));
+        }
+    }
+    Ok(values)
+}
@@ -1,5 +1,11 @@
-use crate::{qry::Expr, TagName};
-use std::str::Chars;
+use crate::error::{Error, Result};
+use crate::glob::is_glob;
+use crate::qry::{Expr, ValueMatch};
+use crate::TagName;
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+use std::str::CharIndices;
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 #[repr(u8)]
@@ -7,101 +13,446 @@ enum Oper {
     Intersect,
     Union,
     Neg,
+    Difference,
+    Xor,
 }
 
 fn precedence(op: Oper) -> u8 {
     match op {
-        Oper::Neg => 3,
-        Oper::Intersect => 2,
+        Oper::Neg => 4,
+        Oper::Intersect | Oper::Difference => 3,
+        Oper::Xor => 2,
         Oper::Union => 1,
     }
 }
 
+/// Case insensitive word operators, quote them to use them as tag names
+fn keyword(ident: &str) -> Option<Oper> {
+    match ident.to_ascii_lowercase().as_str() {
+        "and" => Some(Oper::Intersect),
+        "or" => Some(Oper::Union),
+        "not" => Some(Oper::Neg),
+        _ => None,
+    }
+}
+
 #[derive(Eq, PartialEq, Debug)]
 enum Token {
     Ident(String),
+    Glob(String),
+    Value(ValueMatch),
+    AtLeast(usize, Vec<Expr>),
+    /// `@name` references a saved query
+    Saved(String),
+    /// A saved query once parsed
+    Resolved(Expr),
     ParLeft,
     ParRight,
     Op(Oper),
 }
 
-fn lexer(v: &str) -> Vec<Token> {
-    use Oper::*;
-    use Token::*;
-    fn parse_ident(first: char, c: &mut Chars) -> (Option<char>, String) {
-        let mut chars = vec![first];
-        for v in c {
-            if v.is_alphanumeric() || v == '_' || v == '-' || v == '/' {
-                chars.push(v);
-            } else {
-                return (Some(v), chars.into_iter().collect());
+/// A token along with where it was found in the query
+#[derive(Debug)]
+struct Lexem {
+    tok: Token,
+    off: usize,
+    len: usize,
+}
+
+/// The query does not follow the grammar
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    /// Byte offset in the query of the offending spot
+    pub offset: usize,
+    pub expected: &'static str,
+    pub found: String,
+    /// Error inside the saved query found at `offset`, with offsets in that saved query
+    pub cause: Option<Box<ParseError>>,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(cause) = &self.cause {
+            return write!(f, "in saved query {}: {}", self.found, cause);
+        }
+        write!(
+            f,
+            "expected {}, found {} at offset {}",
+            self.expected, self.found, self.offset
+        )
+    }
+}
+
+const OPERAND: &str = "a tag, a predicate, `(` or `!`";
+const AFTER_OPERAND: &str = "an operator, a tag or end of query";
+
+fn unexpected(src: &str, l: &Lexem, expected: &'static str) -> ParseError {
+    ParseError {
+        offset: l.off,
+        expected,
+        found: format!("`{}`", &src[l.off..l.off + l.len]),
+        cause: None,
+    }
+}
+
+fn end_of_query(src: &str, expected: &'static str) -> ParseError {
+    ParseError {
+        offset: src.len(),
+        expected,
+        found: "end of query".to_string(),
+        cause: None,
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_alphanumeric() || c == '*'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '/' || c == '*' || c == '?'
+}
+
+/// Reads a double quoted string, where `\"` and `\\` stand for `"` and `\`.
+/// With `regex`, other escapes are kept as is for the regex engine.
+/// Returns the string and the offset right after the closing quote.
+fn parse_quoted(
+    v: &str,
+    chars: &mut impl Iterator<Item = (usize, char)>,
+    regex: bool,
+) -> std::result::Result<(String, usize), ParseError> {
+    let mut ident = String::new();
+    while let Some((off, c)) = chars.next() {
+        match c {
+            '"' => return Ok((ident, off + 1)),
+            '\\' => match chars.next() {
+                Some((_, '"')) => ident.push('"'),
+                Some((_, c)) if regex => {
+                    ident.push('\\');
+                    ident.push(c);
+                }
+                Some((_, '\\')) => ident.push('\\'),
+                Some((off, c)) => {
+                    return Err(ParseError {
+                        offset: off,
+                        expected: "`\"` or `\\` after `\\`",
+                        found: format!("`{}`", c),
+                        cause: None,
+                    })
+                }
+                None => break,
+            },
+            c => ident.push(c),
+        }
+    }
+    Err(end_of_query(v, "`\"` to close the quoted string"))
+}
+
+/// Reads an unquoted tag name or glob whose first character `first` is at `off`.
+/// Returns it along with the offset right after it.
+fn read_ident(
+    v: &str,
+    off: usize,
+    first: char,
+    chars: &mut Peekable<CharIndices>,
+) -> (String, usize) {
+    let mut end = off + first.len_utf8();
+    while let Some(&(i, c)) = chars.peek() {
+        if !is_ident_char(c) {
+            break;
+        }
+        end = i + c.len_utf8();
+        chars.next();
+    }
+    (v[off..end].to_string(), end)
+}
+
+/// Error for the next character, or for the end of the query if there is none
+fn unexpected_char(
+    v: &str,
+    chars: &mut Peekable<CharIndices>,
+    expected: &'static str,
+) -> ParseError {
+    match chars.peek() {
+        Some(&(off, c)) => ParseError {
+            offset: off,
+            expected,
+            found: format!("`{}`", c),
+            cause: None,
+        },
+        None => end_of_query(v, expected),
+    }
+}
+
+/// Names saved queries can be referenced by with `@name`
+pub fn is_saved_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(is_ident_start) && chars.all(is_ident_char) && !is_glob(name)
+}
+
+fn skip_whitespace(chars: &mut Peekable<CharIndices>) {
+    while chars.peek().is_some_and(|(_, c)| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+/// `atleast(N, tag, ...)` matches the values having at least N of the tags
+const ATLEAST_KEYWORD: &str = "atleast";
+
+/// Reads the rest of a threshold right after the `atleast` keyword.
+/// Returns None if `atleast` is a plain tag, when not directly followed by `(`.
+fn parse_atleast(
+    v: &str,
+    chars: &mut Peekable<CharIndices>,
+) -> std::result::Result<Option<(Token, usize)>, ParseError> {
+    if !matches!(chars.peek(), Some((_, '('))) {
+        return Ok(None);
+    }
+    chars.next();
+    skip_whitespace(chars);
+    let mut count = String::new();
+    while let Some(&(_, c)) = chars.peek() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        count.push(c);
+        chars.next();
+    }
+    let n = match count.parse() {
+        Ok(n) => n,
+        Err(_) => return Err(unexpected_char(v, chars, "the number of tags to match")),
+    };
+
+    let mut tags = vec![];
+    loop {
+        skip_whitespace(chars);
+        match chars.next() {
+            Some((_, ',')) => {}
+            Some((off, ')')) if !tags.is_empty() => {
+                return Ok(Some((Token::AtLeast(n, tags), off + 1)))
+            }
+            Some((off, c)) => {
+                return Err(ParseError {
+                    offset: off,
+                    expected: if tags.is_empty() { "`,`" } else { "`,` or `)`" },
+                    found: format!("`{}`", c),
+                    cause: None,
+                })
             }
+            None => return Err(end_of_query(v, "`)`")),
+        }
+        skip_whitespace(chars);
+        match chars.next() {
+            Some((_, '"')) => tags.push(Expr::Tag(TagName(parse_quoted(v, chars, false)?.0))),
+            Some((off, c)) if is_ident_start(c) => {
+                let (ident, _) = read_ident(v, off, c, chars);
+                tags.push(if is_glob(&ident) {
+                    Expr::Glob(ident)
+                } else {
+                    Expr::Tag(TagName(ident))
+                });
+            }
+            Some((off, c)) => {
+                return Err(ParseError {
+                    offset: off,
+                    expected: "a tag",
+                    found: format!("`{}`", c),
+                    cause: None,
+                })
+            }
+            None => return Err(end_of_query(v, "a tag")),
         }
-        (None, chars.into_iter().collect())
     }
+}
+
+/// `value:"glob"` and `value~"regex"` filter on the value string
+const VALUE_KEYWORD: &str = "value";
+
+/// Reads the rest of a value predicate right after the `value` keyword.
+/// Returns None if `value` is a plain tag, as in `value ~"x"` or `value & x`.
+fn parse_value_predicate(
+    v: &str,
+    chars: &mut Peekable<CharIndices>,
+) -> std::result::Result<Option<(Token, usize)>, ParseError> {
+    let (op_off, op) = match chars.peek() {
+        Some(&(off, c @ (':' | '~'))) => (off, c),
+        _ => return Ok(None),
+    };
+    let mut after = chars.clone();
+    after.next();
+    match after.next() {
+        Some((_, '"')) => {}
+        // `~` is also the negation, so only a quoted pattern makes it a predicate
+        _ if op == '~' => return Ok(None),
+        Some((off, c)) => {
+            return Err(ParseError {
+                offset: off,
+                expected: "a quoted pattern after `value:`",
+                found: format!("`{}`", c),
+                cause: None,
+            })
+        }
+        None => return Err(end_of_query(v, "a quoted pattern after `value:`")),
+    }
+    chars.next();
+    chars.next();
+    let (pattern, end) = parse_quoted(v, chars, op == '~')?;
+    let pred = if op == ':' {
+        ValueMatch::Glob(pattern)
+    } else {
+        match Regex::new(&pattern) {
+            Ok(re) => ValueMatch::Regex(re),
+            Err(_) => {
+                return Err(ParseError {
+                    offset: op_off + 1,
+                    expected: "a valid regular expression",
+                    found: format!("`{}`", pattern),
+                    cause: None,
+                })
+            }
+        }
+    };
+    Ok(Some((Token::Value(pred), end)))
+}
+
+fn lexer(v: &str) -> std::result::Result<Vec<Lexem>, ParseError> {
+    use Oper::*;
+    use Token::*;
 
     let mut tokens = vec![];
-    let mut c = v.chars();
-    let mut nexttok = c.next();
-    while nexttok.is_some() {
-        let tok = match nexttok {
-            Some('(') => ParLeft,
-            Some(')') => ParRight,
-            Some('!') => Op(Neg),
-            Some('~') => Op(Neg),
-            Some('&') => Op(Intersect),
-            Some('|') => Op(Union),
-            Some('+') => Op(Union),
-            Some(t) if t.is_alphanumeric() => {
-                let (nextok, ident) = parse_ident(t, &mut c);
-                nexttok = nextok;
-                tokens.push(Ident(ident));
+    let mut chars = v.char_indices().peekable();
+    while let Some((off, c)) = chars.next() {
+        let tok = match c {
+            '(' => ParLeft,
+            ')' => ParRight,
+            '!' => Op(Neg),
+            '~' => Op(Neg),
+            '&' => Op(Intersect),
+            '|' => Op(Union),
+            '+' => Op(Union),
+            '^' => Op(Xor),
+            // only an operator at the start of a token, `a-b` is a tag
+            '-' => Op(Difference),
+            c if is_ident_start(c) => {
+                let (ident, end) = read_ident(v, off, c, &mut chars);
+                if let Some(op) = keyword(&ident) {
+                    tokens.push(Lexem {
+                        tok: Op(op),
+                        off,
+                        len: end - off,
+                    });
+                    continue;
+                }
+                let special = match ident.as_str() {
+                    VALUE_KEYWORD => parse_value_predicate(v, &mut chars)?,
+                    ATLEAST_KEYWORD => parse_atleast(v, &mut chars)?,
+                    _ => None,
+                };
+                if let Some((tok, end)) = special {
+                    tokens.push(Lexem {
+                        tok,
+                        off,
+                        len: end - off,
+                    });
+                    continue;
+                }
+                tokens.push(Lexem {
+                    tok: if is_glob(&ident) {
+                        Glob(ident)
+                    } else {
+                        Ident(ident)
+                    },
+                    off,
+                    len: end - off,
+                });
                 continue;
             }
-            Some(_) => {
-                nexttok = c.next();
+            '@' => match chars.peek() {
+                Some(&(start, c)) if is_ident_start(c) => {
+                    chars.next();
+                    let (name, end) = read_ident(v, start, c, &mut chars);
+                    tokens.push(Lexem {
+                        tok: Saved(name),
+                        off,
+                        len: end - off,
+                    });
+                    continue;
+                }
+                _ => {
+                    return Err(unexpected_char(
+                        v,
+                        &mut chars,
+                        "the name of a saved query after `@`",
+                    ))
+                }
+            },
+            '"' => {
+                let (ident, end) = parse_quoted(v, &mut chars, false)?;
+                tokens.push(Lexem {
+                    tok: Ident(ident),
+                    off,
+                    len: end - off,
+                });
                 continue;
             }
-            None => break,
+            c if c.is_whitespace() => continue,
+            c => {
+                return Err(ParseError {
+                    offset: off,
+                    expected: "a tag, an operator or a parenthesis",
+                    found: format!("`{}`", c),
+                    cause: None,
+                })
+            }
         };
-        nexttok = c.next();
-        tokens.push(tok);
+        tokens.push(Lexem {
+            tok,
+            off,
+            len: c.len_utf8(),
+        });
     }
-    tokens
+    Ok(tokens)
 }
 
-fn shunting_yard(toks: Vec<Token>) -> Vec<Token> {
+/// Moves the operators binding at least as tightly as `prec` to the output
+fn pop_ops(opstack: &mut Vec<Lexem>, out: &mut Vec<Lexem>, prec: u8) {
+    while let Some(l) = opstack.last() {
+        match l.tok {
+            Token::Op(op) if precedence(op) >= prec => out.extend(opstack.pop()),
+            _ => break,
+        }
+    }
+}
+
+/// Converts to reverse polish notation, checking the tokens are in a valid order on the way
+fn shunting_yard(src: &str, toks: Vec<Lexem>) -> std::result::Result<Vec<Lexem>, ParseError> {
     use Token::*;
-    let mut opstack: Vec<Token> = vec![];
+    let mut opstack: Vec<Lexem> = vec![];
     let mut out = Vec::with_capacity(toks.len());
     let mut lastwasident = false;
+    let empty = toks.is_empty();
     for t in toks {
-        match t {
-            Op(Oper::Union) | Op(Oper::Intersect) | ParRight => {}
-            Ident(_) | ParLeft | Op(Oper::Neg) => {
+        match t.tok {
+            Op(Oper::Union | Oper::Intersect | Oper::Difference | Oper::Xor) | ParRight => {
+                if !lastwasident {
+                    return Err(unexpected(src, &t, OPERAND));
+                }
+            }
+            Ident(_) | Glob(_) | Value(_) | AtLeast(..) | Saved(_) | Resolved(_) | ParLeft
+            | Op(Oper::Neg) => {
                 if lastwasident {
-                    while opstack
-                        .last()
-                        .and_then(|x| {
-                            if let Op(op) = *x {
-                                Some(precedence(op))
-                            } else {
-                                None
-                            }
-                        })
-                        .unwrap_or(u8::MIN)
-                        >= precedence(Oper::Intersect)
-                    {
-                        out.push(opstack.pop().unwrap());
-                    }
-                    opstack.push(Token::Op(Oper::Intersect));
+                    pop_ops(&mut opstack, &mut out, precedence(Oper::Intersect));
+                    opstack.push(Lexem {
+                        tok: Op(Oper::Intersect),
+                        off: t.off,
+                        len: 0,
+                    });
                 }
             }
         }
 
         lastwasident = false;
-        match t {
-            Ident(_) => {
+        match t.tok {
+            Ident(_) | Glob(_) | Value(_) | AtLeast(..) | Saved(_) | Resolved(_) => {
                 out.push(t);
                 lastwasident = true;
             }
@@ -109,60 +460,77 @@ fn shunting_yard(toks: Vec<Token>) -> Vec<Token> {
             ParRight => {
                 loop {
                     match opstack.pop() {
-                        None => break,
-                        Some(ParLeft) => break,
+                        None => return Err(unexpected(src, &t, AFTER_OPERAND)),
+                        Some(Lexem { tok: ParLeft, .. }) => break,
                         Some(v) => out.push(v),
                     }
                 }
                 lastwasident = true;
             }
+            // prefix, so nothing before it can be applied yet
+            Op(Oper::Neg) => opstack.push(t),
             Op(op) => {
-                while opstack
-                    .last()
-                    .and_then(|x| {
-                        if let Op(op) = *x {
-                            Some(precedence(op))
-                        } else {
-                            None
-                        }
-                    })
-                    .unwrap_or(u8::MIN)
-                    >= precedence(op)
-                {
-                    out.push(opstack.pop().unwrap());
-                }
+                pop_ops(&mut opstack, &mut out, precedence(op));
                 opstack.push(t);
             }
         }
     }
-    out.extend(opstack.into_iter().rev().filter(|x| x != &ParLeft));
-    out
+    if !lastwasident && !empty {
+        return Err(end_of_query(src, OPERAND));
+    }
+    while let Some(l) = opstack.pop() {
+        if l.tok == ParLeft {
+            return Err(end_of_query(src, "`)`"));
+        }
+        out.push(l);
+    }
+    Ok(out)
 }
 
-fn rpn_to_expr(tokens: Vec<Token>) -> Option<Expr> {
+fn rpn_to_expr(src: &str, tokens: Vec<Lexem>) -> std::result::Result<Option<Expr>, ParseError> {
     let mut stack = vec![];
     for t in tokens {
-        match t {
+        match t.tok {
             Token::Ident(x) => stack.push(Expr::Tag(TagName(x))),
+            Token::Glob(x) => stack.push(Expr::Glob(x)),
+            Token::Value(x) => stack.push(Expr::Value(x)),
+            Token::AtLeast(n, tags) => stack.push(Expr::AtLeast(n, tags)),
+            Token::Resolved(x) => stack.push(x),
+            Token::Saved(_) => unreachable!("saved queries should have been resolved by now"),
             Token::Op(Oper::Neg) => {
                 if let Some(x) = stack.pop() {
                     stack.push(Expr::Not(Box::new(x)));
                 } else {
-                    panic!("expected expr to negate");
+                    return Err(unexpected(src, &t, "an expression to negate"));
                 }
             }
             Token::Op(Oper::Union) => {
                 if let (Some(a), Some(b)) = (stack.pop(), stack.pop()) {
-                    stack.push(Expr::Or(Box::new(a), Box::new(b)));
+                    stack.push(Expr::Or(Box::new(b), Box::new(a)));
                 } else {
-                    panic!("expected 2 expr to unionize");
+                    return Err(unexpected(src, &t, "2 expressions to unionize"));
                 }
             }
             Token::Op(Oper::Intersect) => {
                 if let (Some(a), Some(b)) = (stack.pop(), stack.pop()) {
-                    stack.push(Expr::And(Box::new(a), Box::new(b)));
+                    stack.push(Expr::And(Box::new(b), Box::new(a)));
+                } else {
+                    return Err(unexpected(src, &t, "2 expressions to intersect"));
+                }
+            }
+            // `b - a` is `b & !a`
+            Token::Op(Oper::Difference) => {
+                if let (Some(a), Some(b)) = (stack.pop(), stack.pop()) {
+                    stack.push(Expr::And(Box::new(b), Box::new(Expr::Not(Box::new(a)))));
+                } else {
+                    return Err(unexpected(src, &t, "2 expressions to subtract"));
+                }
+            }
+            Token::Op(Oper::Xor) => {
+                if let (Some(a), Some(b)) = (stack.pop(), stack.pop()) {
+                    stack.push(Expr::Xor(Box::new(b), Box::new(a)));
                 } else {
-                    panic!("expected 2 expr to intersect");
+                    return Err(unexpected(src, &t, "2 expressions to xor"));
                 }
             }
             Token::ParLeft | Token::ParRight => {
@@ -171,19 +539,98 @@ fn rpn_to_expr(tokens: Vec<Token>) -> Option<Expr> {
         }
     }
     if stack.len() > 1 {
-        panic!("multiple expr left in stack");
+        return Err(end_of_query(src, "a single expression"));
     }
-    stack.pop()
+    Ok(stack.pop())
 }
 
-pub fn parse_query(v: &str) -> Option<Expr> {
-    let mut lexems = lexer(v);
-    if std::env::var("DEBUG").is_ok() {
-        println!("lexems:  {:?}", lexems);
+/// Looks the source of a saved query up by name
+pub type SavedQueries<'a> = dyn Fn(&str) -> Result<Option<String>> + 'a;
+
+/// Parses the query, replacing each `@name` by the saved query it references
+pub fn parse_query(v: &str, saved: &SavedQueries) -> Result<Option<Expr>> {
+    parse_with(v, saved, &mut vec![])
+}
+
+/// Parses the query about to be saved as `name`, so it cannot reference itself
+pub fn parse_saved(name: &str, v: &str, saved: &SavedQueries) -> Result<Option<Expr>> {
+    parse_with(v, saved, &mut vec![name.to_string()])
+}
+
+/// `parents` are the saved queries being parsed, which would loop if referenced again
+fn parse_with(v: &str, saved: &SavedQueries, parents: &mut Vec<String>) -> Result<Option<Expr>> {
+    let mut lexems = shunting_yard(v, lexer(v)?)?;
+    for l in &mut lexems {
+        if let Token::Saved(name) = &l.tok {
+            l.tok = Token::Resolved(resolve(v, l, name, saved, parents)?);
+        }
     }
-    lexems = shunting_yard(lexems);
-    if std::env::var("DEBUG").is_ok() {
-        println!("shunted: {:?}", lexems);
+    Ok(rpn_to_expr(v, lexems)?)
+}
+
+/// Parses the saved query referenced at `l`, errors inside it are reported at the reference
+/// with the error in the saved query as their cause
+fn resolve(
+    src: &str,
+    l: &Lexem,
+    name: &str,
+    saved: &SavedQueries,
+    parents: &mut Vec<String>,
+) -> Result<Expr> {
+    if parents.iter().any(|p| p == name) {
+        return Err(unexpected(src, l, "a saved query that does not reference itself").into());
+    }
+    let Some(saved_src) = saved(name)? else {
+        return Err(unexpected(src, l, "the name of a saved query").into());
+    };
+    parents.push(name.to_string());
+    let expr = parse_with(&saved_src, saved, parents);
+    parents.pop();
+    match expr {
+        Ok(Some(x)) => Ok(x),
+        Ok(None) => Err(unexpected(src, l, "a saved query that is not empty").into()),
+        Err(Error::Parse(e)) => Err(Error::Parse(ParseError {
+            cause: Some(Box::new(e)),
+            ..unexpected(src, l, "a valid saved query")
+        })),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(q: &str) -> String {
+        parse_query(q, &|_| Ok(None)).unwrap().unwrap().to_string()
+    }
+
+    #[test]
+    fn repeated_negation() {
+        assert_eq!(parsed("!!a"), "!!a");
+        assert_eq!(parsed("not not a"), "!!a");
+        assert_eq!(parsed("a & !!b"), "(a & !!b)");
+        assert_eq!(parsed("!!a & b"), "(!!a & b)");
+    }
+
+    #[test]
+    fn negated_right_operand() {
+        assert_eq!(parsed("a - !b"), "(a & !!b)");
+        assert_eq!(parsed("a ^ !b"), "(a ^ !b)");
+        assert_eq!(parsed("a | !b & c"), "(a | (!b & c))");
+        assert_eq!(parsed("!a b"), "(!a & b)");
+    }
+
+    #[test]
+    fn error_in_saved_query() {
+        let saved = |name: &str| Ok((name == "fav2").then(|| "a &".to_string()));
+        let Err(Error::Parse(e)) = parse_query("b | @fav2", &saved) else {
+            panic!("saved query should not parse");
+        };
+        assert_eq!(e.offset, 4);
+        assert_eq!(e.found, "`@fav2`");
+        let cause = e.cause.expect("error should come from the saved query");
+        assert_eq!(cause.offset, 3);
+        assert_eq!(cause.found, "end of query");
     }
-    rpn_to_expr(lexems)
 }
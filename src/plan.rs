@@ -0,0 +1,506 @@
+use crate::error::Result;
+use crate::kernels::{self, as_u32, into_ids};
+use crate::postings::PostingList;
+use crate::qry::{execute_atleast, values_match, Atom, TagCtx, ValueMatch};
+use crate::ID;
+use std::borrow::Cow;
+use std::fmt::{Display, Formatter};
+use std::iter::Peekable;
+
+/// Sets checked by walking them along the candidates rather than looking up each candidate,
+/// as long as they hold at most this many IDs per candidate
+const MERGE_RATIO: usize = 8;
+
+/// A set of IDs to intersect, either read from a tag file or computed
+pub enum IdSet<'a> {
+    Posting(PostingList<'a>),
+    Ids(Vec<ID>),
+}
+
+/// Sorted IDs with random access, to gallop through
+#[derive(Copy, Clone)]
+enum Sorted<'a> {
+    Bytes(&'a [u8], usize),
+    Ids(&'a [ID]),
+}
+
+impl Sorted<'_> {
+    fn len(&self) -> usize {
+        match *self {
+            Sorted::Bytes(_, count) => count,
+            Sorted::Ids(ids) => ids.len(),
+        }
+    }
+
+    fn get(&self, i: usize) -> ID {
+        match *self {
+            Sorted::Bytes(bytes, _) => ID(crate::qry::read_int(bytes, i)),
+            Sorted::Ids(ids) => ids[i],
+        }
+    }
+}
+
+impl<'a> IdSet<'a> {
+    pub fn len(&self) -> usize {
+        match self {
+            IdSet::Posting(p) => p.len(),
+            IdSet::Ids(ids) => ids.len(),
+        }
+    }
+
+    pub fn contains(&self, id: ID) -> bool {
+        match self {
+            IdSet::Posting(p) => p.contains(id),
+            IdSet::Ids(ids) => ids.binary_search(&id).is_ok(),
+        }
+    }
+
+    pub fn iter(&self) -> Box<dyn Iterator<Item = ID> + '_> {
+        match self {
+            IdSet::Posting(p) => Box::new(p.iter()),
+            IdSet::Ids(ids) => Box::new(ids.iter().copied()),
+        }
+    }
+
+    /// The IDs as a slice, borrowed from the tag file when it is an array
+    fn to_u32s(&self) -> Cow<'_, [u32]> {
+        match self {
+            IdSet::Posting(p) => match p.as_array() {
+                Some(bytes) => kernels::ids(bytes),
+                None => Cow::Owned(p.to_vec()),
+            },
+            IdSet::Ids(ids) => Cow::Borrowed(as_u32(ids)),
+        }
+    }
+
+    fn sorted(&self) -> Option<Sorted<'_>> {
+        match self {
+            IdSet::Posting(p) => p.as_array().map(|ids| Sorted::Bytes(ids, p.len())),
+            IdSet::Ids(ids) => Some(Sorted::Ids(ids)),
+        }
+    }
+
+    fn is_bitmap(&self) -> bool {
+        matches!(self, IdSet::Posting(p) if p.is_bitmap())
+    }
+}
+
+/// How a conjunct is evaluated
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Strategy {
+    /// Enumerates the set to produce the candidates
+    Scan,
+    /// Looks every candidate up on its own: binary search, skip table or bit test
+    Probe,
+    /// Walks the set along the candidates, for sets about as large as the candidates
+    Merge,
+    /// Walks the set with exponential search, for sets much larger than the candidates
+    Gallop,
+    /// Reads the value of every candidate
+    Filter,
+}
+
+#[derive(Debug)]
+pub struct Conjunct {
+    pub atom: Atom,
+    pub positive: bool,
+    /// Number of IDs in the set, None for value predicates
+    pub size: Option<usize>,
+    pub strategy: Strategy,
+    /// Index of the resolved set
+    set: Option<usize>,
+}
+
+/// How a conjunction of the DNF is executed
+#[derive(Debug)]
+pub struct AndPlan {
+    /// The conjunction can never match, as in `p & !p` or with a tag that does not exist
+    pub never: bool,
+    /// Produces the candidates, or None when every value is a candidate
+    pub driver: Option<Conjunct>,
+    /// Number of candidates
+    pub candidates: usize,
+    /// Checked in order against every candidate
+    pub checks: Vec<Conjunct>,
+    /// Estimated number of matches, assuming the tags are independent
+    pub estimate: usize,
+    /// What running the conjunction did, None if it did not run
+    pub actual: Option<AndStats>,
+}
+
+/// What running a conjunction did
+#[derive(Copy, Clone, Debug)]
+pub struct AndStats {
+    /// Candidates checked
+    pub scanned: usize,
+    pub matches: usize,
+}
+
+impl AndPlan {
+    /// Work spent per match, lower is better to reach the limit fast
+    fn cost(&self) -> f64 {
+        if self.never {
+            return 0.0;
+        }
+        self.candidates as f64 / (self.estimate as f64 + 1.0)
+    }
+}
+
+/// How a whole query is executed
+#[derive(Debug)]
+pub enum QueryPlan {
+    /// The query is empty, every value matches
+    All,
+    /// The branches of the DNF, in execution order
+    Dnf(Vec<AndPlan>),
+    /// The DNF would have too many conjunctions, the expression tree is evaluated directly
+    Tree { conjunctions: usize },
+}
+
+impl Display for Conjunct {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let strategy = match self.strategy {
+            Strategy::Scan => "scan",
+            Strategy::Probe => "probe",
+            Strategy::Merge => "merge",
+            Strategy::Gallop => "gallop",
+            Strategy::Filter => "filter",
+        };
+        write!(f, "{:<6} ", strategy)?;
+        if !self.positive {
+            write!(f, "!")?;
+        }
+        write!(f, "{}", self.atom)?;
+        if let Some(size) = self.size {
+            write!(f, " ({})", size)?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for AndPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.never {
+            return writeln!(f, "  never matches");
+        }
+        match &self.driver {
+            Some(driver) => writeln!(f, "  {}", driver)?,
+            None => writeln!(f, "  scan   all values ({})", self.candidates)?,
+        }
+        for check in &self.checks {
+            writeln!(f, "  {}", check)?;
+        }
+        write!(f, "  ~{} matches", self.estimate)?;
+        match self.actual {
+            Some(stats) => writeln!(
+                f,
+                ", found {} out of {} candidates",
+                stats.matches, stats.scanned
+            ),
+            None => writeln!(f),
+        }
+    }
+}
+
+impl Display for QueryPlan {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueryPlan::All => writeln!(f, "every value"),
+            QueryPlan::Tree { conjunctions } => writeln!(
+                f,
+                "expression tree, the DNF would have {} conjunctions",
+                conjunctions
+            ),
+            QueryPlan::Dnf(branches) => {
+                for (i, branch) in branches.iter().enumerate() {
+                    writeln!(f, "branch {}:", i + 1)?;
+                    branch.fmt(f)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn never() -> AndPlan {
+    AndPlan {
+        never: true,
+        driver: None,
+        candidates: 0,
+        checks: vec![],
+        estimate: 0,
+        actual: None,
+    }
+}
+
+fn strategy(candidates: usize, set: &IdSet) -> Strategy {
+    if set.is_bitmap() {
+        Strategy::Probe
+    } else if set.len() <= candidates.saturating_mul(MERGE_RATIO) {
+        Strategy::Merge
+    } else if set.sorted().is_some() {
+        Strategy::Gallop
+    } else {
+        Strategy::Probe
+    }
+}
+
+/// Resolves the sets of a conjunction and decides in which order and how they are intersected:
+/// the smallest positive set drives, the others are checked from the most to the least selective,
+/// and values are only read for the candidates left at the end.
+pub fn plan_and<'a>(
+    ctx: &'a TagCtx,
+    mut needs: Vec<(Atom, bool)>,
+) -> Result<(AndPlan, Vec<IdSet<'a>>)> {
+    needs.sort();
+    needs.dedup();
+
+    // detect p & !p
+    for w in needs.windows(2) {
+        if w[0].0 == w[1].0 && w[0].1 != w[1].1 {
+            return Ok((never(), vec![]));
+        }
+    }
+
+    let mut sets = Vec::with_capacity(needs.len());
+    let mut conjuncts = vec![];
+    let mut filters = vec![];
+    for (atom, positive) in needs {
+        let set = match &atom {
+            Atom::Tag(tag) => match ctx.mapped_tags.get(tag) {
+                Some(m) => IdSet::Posting(PostingList::new(m)?),
+                None if positive => return Ok((never(), vec![])),
+                None => continue,
+            },
+            Atom::AtLeast(n, tags) => IdSet::Ids(execute_atleast(ctx, *n, tags)?),
+            Atom::Value(_) => {
+                filters.push(Conjunct {
+                    atom,
+                    positive,
+                    size: None,
+                    strategy: Strategy::Filter,
+                    set: None,
+                });
+                continue;
+            }
+        };
+        conjuncts.push(Conjunct {
+            atom,
+            positive,
+            size: Some(set.len()),
+            strategy: Strategy::Probe,
+            set: Some(sets.len()),
+        });
+        sets.push(set);
+    }
+
+    let all = PostingList::new(&ctx.allmap)?.len();
+    // number of values each conjunct lets through
+    let kept = |c: &Conjunct| {
+        let size = c.size.unwrap_or(all);
+        if c.positive {
+            size
+        } else {
+            all.saturating_sub(size)
+        }
+    };
+    conjuncts.sort_by_key(kept);
+
+    // enumerating a positive set always beats going through every value
+    let driver = conjuncts.iter().position(|c| c.positive).map(|i| {
+        let mut c = conjuncts.remove(i);
+        c.strategy = Strategy::Scan;
+        c
+    });
+    let candidates = driver.as_ref().map_or(all, kept);
+    let mut estimate = candidates as f64;
+    for c in &mut conjuncts {
+        c.strategy = strategy(estimate.ceil() as usize, &sets[c.set.unwrap_or(0)]);
+        estimate *= kept(c) as f64 / all.max(1) as f64;
+    }
+    conjuncts.extend(filters);
+
+    Ok((
+        AndPlan {
+            never: false,
+            driver,
+            candidates,
+            checks: conjuncts,
+            estimate: estimate.round() as usize,
+            actual: None,
+        },
+        sets,
+    ))
+}
+
+/// Orders the branches of the DNF so the limit is reached with the least work:
+/// once it is, the next branches stop at the last ID kept
+pub fn order_branches<T>(branches: &mut [(AndPlan, T)]) {
+    branches.sort_by(|a, b| a.0.cost().total_cmp(&b.0.cost()));
+}
+
+/// Walks a set along increasing candidates to tell whether they are in it
+enum Cursor<'a> {
+    Probe(&'a IdSet<'a>),
+    Merge(Peekable<Box<dyn Iterator<Item = ID> + 'a>>),
+    Gallop { ids: Sorted<'a>, pos: usize },
+}
+
+impl<'a> Cursor<'a> {
+    fn new(set: &'a IdSet<'a>, strategy: Strategy) -> Self {
+        match (strategy, set.sorted()) {
+            (Strategy::Merge, _) => Cursor::Merge(set.iter().peekable()),
+            (Strategy::Gallop, Some(ids)) => Cursor::Gallop { ids, pos: 0 },
+            _ => Cursor::Probe(set),
+        }
+    }
+
+    /// Whether `id` is in the set, ids must be given in increasing order
+    fn seek(&mut self, id: ID) -> bool {
+        match self {
+            Cursor::Probe(set) => set.contains(id),
+            Cursor::Merge(iter) => {
+                while iter.next_if(|&x| x < id).is_some() {}
+                iter.peek() == Some(&id)
+            }
+            Cursor::Gallop { ids, pos } => {
+                let len = ids.len();
+                if *pos >= len || ids.get(*pos) >= id {
+                    return *pos < len && ids.get(*pos) == id;
+                }
+                // ids[lo] < id, find the first position holding at least id past it
+                let mut lo = *pos;
+                let mut step = 1;
+                while lo + step < len && ids.get(lo + step) < id {
+                    lo += step;
+                    step *= 2;
+                }
+                let (mut left, mut right) = (lo + 1, (lo + step + 1).min(len));
+                while left < right {
+                    let middle = (left + right) / 2;
+                    if ids.get(middle) < id {
+                        left = middle + 1;
+                    } else {
+                        right = middle;
+                    }
+                }
+                *pos = left;
+                left < len && ids.get(left) == id
+            }
+        }
+    }
+}
+
+/// Runs a conjunction following its plan, keeping the first `limit` matches past `after`
+/// and before `before`
+pub fn run_and(
+    ctx: &TagCtx,
+    plan: &AndPlan,
+    sets: &[IdSet],
+    limit: usize,
+    after: Option<ID>,
+    before: Option<ID>,
+) -> Result<(Vec<ID>, AndStats)> {
+    let mut out = vec![];
+    let mut scanned = 0;
+    if plan.never {
+        return Ok((
+            out,
+            AndStats {
+                scanned,
+                matches: 0,
+            },
+        ));
+    }
+    let preds: Vec<(&ValueMatch, bool)> = plan
+        .checks
+        .iter()
+        .filter_map(|c| match &c.atom {
+            Atom::Value(p) => Some((p, c.positive)),
+            _ => None,
+        })
+        .collect();
+    let all = PostingList::new(&ctx.allmap)?;
+    if limit >= plan.candidates {
+        let (mut ids, stats) = run_and_sets(ctx, plan, sets, all, &preds)?;
+        if let Some(after) = after {
+            ids.drain(..ids.partition_point(|&id| id <= after));
+        }
+        if let Some(before) = before {
+            ids.truncate(ids.partition_point(|&id| id < before));
+        }
+        ids.truncate(limit);
+        let matches = ids.len();
+        return Ok((ids, AndStats { matches, ..stats }));
+    }
+
+    let candidates = match plan.driver.as_ref().and_then(|d| d.set) {
+        Some(set) => sets[set].iter(),
+        None => Box::new(all.iter()),
+    };
+    let candidates = candidates
+        .skip_while(|&id| after.is_some_and(|a| id <= a))
+        .take_while(|&id| before.is_none_or(|b| id < b));
+    let mut cursors: Vec<_> = plan
+        .checks
+        .iter()
+        .filter_map(|c| Some((Cursor::new(&sets[c.set?], c.strategy), c.positive)))
+        .collect();
+
+    for id in candidates {
+        if out.len() >= limit {
+            break;
+        }
+        scanned += 1;
+        if cursors.iter_mut().all(|(c, pos)| c.seek(id) == *pos) && values_match(ctx, id, &preds)? {
+            out.push(id);
+        }
+    }
+    let matches = out.len();
+    Ok((out, AndStats { scanned, matches }))
+}
+
+/// Runs a whole conjunction a set at a time with the kernels, when every match is wanted anyway
+fn run_and_sets(
+    ctx: &TagCtx,
+    plan: &AndPlan,
+    sets: &[IdSet],
+    all: PostingList,
+    preds: &[(&ValueMatch, bool)],
+) -> Result<(Vec<ID>, AndStats)> {
+    let mut ids = match plan.driver.as_ref().and_then(|d| d.set) {
+        Some(set) => sets[set].to_u32s().into_owned(),
+        None => all.to_vec(),
+    };
+    let scanned = ids.len();
+    let mut next = Vec::with_capacity(ids.len());
+    for c in &plan.checks {
+        let Some(set) = c.set.map(|i| &sets[i]) else {
+            continue;
+        };
+        if c.strategy == Strategy::Probe {
+            ids.retain(|&id| set.contains(ID(id)) == c.positive);
+            continue;
+        }
+        next.clear();
+        if c.positive {
+            kernels::intersect(&ids, &set.to_u32s(), &mut next);
+        } else {
+            kernels::difference(&ids, &set.to_u32s(), &mut next);
+        }
+        std::mem::swap(&mut ids, &mut next);
+    }
+
+    let mut out = into_ids(ids);
+    if !preds.is_empty() {
+        let mut kept = Vec::with_capacity(out.len());
+        for id in out {
+            if values_match(ctx, id, preds)? {
+                kept.push(id);
+            }
+        }
+        out = kept;
+    }
+    let matches = out.len();
+    Ok((out, AndStats { scanned, matches }))
+}
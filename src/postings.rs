@@ -0,0 +1,528 @@
+use crate::error::{Error, Result};
+use crate::qry::read_int;
+use crate::ID;
+
+/// Tag files hold a sorted set of IDs in one of three encodings, chosen per tag by whichever is smallest.
+///
+/// Every encoding starts with `[kind: u32][count: u32]`, an empty file is an empty list.
+/// - Array:  `[id: u32 * count]`
+/// - Packed: `[nblocks: u32][(first id: u32, byte offset: u32) * nblocks][blocks]`
+///   where each block holds the varint encoded deltas following its first id.
+///   The skip table lets `contains` jump directly to the right block.
+/// - Bitmap: `[base: u32][bits]` where bit i is set if base + i is in the list
+const KIND_ARRAY: u32 = 0;
+const KIND_PACKED: u32 = 1;
+const KIND_BITMAP: u32 = 2;
+
+const HEADER_SIZE: usize = 8;
+const BLOCK_SIZE: usize = 128;
+
+#[derive(Copy, Clone)]
+pub enum PostingList<'a> {
+    Array {
+        ids: &'a [u8],
+        count: usize,
+    },
+    Packed {
+        skips: &'a [u8],
+        nblocks: usize,
+        blocks: &'a [u8],
+        count: usize,
+    },
+    Bitmap {
+        base: u32,
+        bits: &'a [u8],
+        count: usize,
+    },
+}
+
+impl<'a> PostingList<'a> {
+    pub fn new(map: &'a [u8]) -> Result<Self> {
+        let corrupted = || Error::Corrupted("tag file is truncated");
+        if map.is_empty() {
+            return Ok(PostingList::Array { ids: &[], count: 0 });
+        }
+        if map.len() < HEADER_SIZE {
+            return Err(corrupted());
+        }
+        let count = read_int(map, 1) as usize;
+        let kind = read_int(map, 0);
+        if kind != KIND_ARRAY && map.len() < HEADER_SIZE + 4 {
+            return Err(corrupted());
+        }
+        Ok(match kind {
+            KIND_ARRAY => {
+                if map.len() != HEADER_SIZE + count * 4 {
+                    return Err(corrupted());
+                }
+                PostingList::Array {
+                    ids: &map[HEADER_SIZE..],
+                    count,
+                }
+            }
+            KIND_PACKED => {
+                let nblocks = read_int(map, 2) as usize;
+                if nblocks != count.div_ceil(BLOCK_SIZE) {
+                    return Err(Error::Corrupted("tag file has the wrong number of blocks"));
+                }
+                let skips_end = HEADER_SIZE + 4 + nblocks * 8;
+                let skips = map.get(HEADER_SIZE + 4..skips_end).ok_or_else(corrupted)?;
+                let blocks = &map[skips_end..];
+                check_blocks(skips, blocks, count)?;
+                PostingList::Packed {
+                    skips,
+                    nblocks,
+                    blocks,
+                    count,
+                }
+            }
+            KIND_BITMAP => {
+                let base = read_int(map, 2);
+                let bits = &map[HEADER_SIZE + 4..];
+                if base as u64 + bits.len() as u64 * 8 > 1 << 32 {
+                    return Err(Error::Corrupted("tag file has IDs out of range"));
+                }
+                if bits.iter().map(|b| b.count_ones() as usize).sum::<usize>() != count {
+                    return Err(Error::Corrupted("tag file has the wrong count"));
+                }
+                PostingList::Bitmap { base, bits, count }
+            }
+            _ => return Err(Error::Corrupted("unknown tag file encoding")),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        match *self {
+            PostingList::Array { count, .. }
+            | PostingList::Packed { count, .. }
+            | PostingList::Bitmap { count, .. } => count,
+        }
+    }
+
+    pub fn contains(&self, needle: ID) -> bool {
+        match *self {
+            PostingList::Array { ids, count } => {
+                let mut left = 0;
+                let mut right = count;
+                while left < right {
+                    let middle = (left + right) / 2;
+                    let v = read_int(ids, middle);
+                    if v == needle.0 {
+                        return true;
+                    }
+                    if v > needle.0 {
+                        right = middle;
+                    } else {
+                        left = middle + 1;
+                    }
+                }
+                false
+            }
+            PostingList::Packed {
+                skips,
+                nblocks,
+                blocks,
+                count,
+            } => {
+                // find the last block starting at or before needle
+                let mut left = 0;
+                let mut right = nblocks;
+                while left < right {
+                    let middle = (left + right) / 2;
+                    if read_int(skips, middle * 2) <= needle.0 {
+                        left = middle + 1;
+                    } else {
+                        right = middle;
+                    }
+                }
+                if left == 0 {
+                    return false;
+                }
+                let block = left - 1;
+                BlockIter::new(skips, blocks, block, count)
+                    .take_while(|&v| v <= needle.0)
+                    .any(|v| v == needle.0)
+            }
+            PostingList::Bitmap { base, bits, .. } => {
+                if needle.0 < base {
+                    return false;
+                }
+                let i = (needle.0 - base) as usize;
+                bits.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0)
+            }
+        }
+    }
+
+    pub fn iter(&self) -> PostingIter<'a> {
+        match *self {
+            PostingList::Array { ids, count } => PostingIter(IterState::Array { ids, i: 0, count }),
+            PostingList::Packed {
+                skips,
+                nblocks,
+                blocks,
+                count,
+            } => PostingIter(IterState::Packed {
+                skips,
+                nblocks,
+                blocks,
+                count,
+                block: 0,
+                cur: None,
+            }),
+            PostingList::Bitmap { base, bits, .. } => {
+                PostingIter(IterState::Bitmap { base, bits, i: 0 })
+            }
+        }
+    }
+
+    /// The raw little endian IDs when the list is stored as an array
+    pub fn as_array(&self) -> Option<&'a [u8]> {
+        match *self {
+            PostingList::Array { ids, .. } => Some(ids),
+            _ => None,
+        }
+    }
+
+    pub fn is_bitmap(&self) -> bool {
+        matches!(self, PostingList::Bitmap { .. })
+    }
+
+    pub fn to_vec(self) -> Vec<u32> {
+        let mut v = Vec::with_capacity(self.len());
+        v.extend(self.iter().map(|x| x.0));
+        v
+    }
+}
+
+/// Decodes every block of a packed list once, so iterating it never meets bytes that do not decode
+/// and writing it back never drops IDs
+fn check_blocks(skips: &[u8], blocks: &[u8], count: usize) -> Result<()> {
+    let unsorted = || Error::Corrupted("tag file is not sorted");
+    let nblocks = skips.len() / 8;
+    let mut prev = None;
+    for block in 0..nblocks {
+        let start = read_int(skips, block * 2 + 1) as usize;
+        let end = if block + 1 < nblocks {
+            read_int(skips, block * 2 + 3) as usize
+        } else {
+            blocks.len()
+        };
+        let bytes = blocks
+            .get(start..end)
+            .ok_or(Error::Corrupted("tag file has a block out of bounds"))?;
+        let mut last = read_int(skips, block * 2);
+        if prev.is_some_and(|p| last <= p) {
+            return Err(unsorted());
+        }
+        let mut pos = 0;
+        for _ in 1..(count - block * BLOCK_SIZE).min(BLOCK_SIZE) {
+            let delta = read_varint(bytes, &mut pos)?;
+            last = last
+                .checked_add(delta)
+                .filter(|_| delta > 0)
+                .ok_or_else(unsorted)?;
+        }
+        if pos != bytes.len() {
+            return Err(Error::Corrupted("tag file has a block longer than its IDs"));
+        }
+        prev = Some(last);
+    }
+    Ok(())
+}
+
+/// Decodes a single block of a packed list, checked by `check_blocks`
+struct BlockIter<'a> {
+    blocks: &'a [u8],
+    pos: usize,
+    left: usize,
+    last: Option<u32>,
+    first: u32,
+}
+
+impl<'a> BlockIter<'a> {
+    fn new(skips: &'a [u8], blocks: &'a [u8], block: usize, count: usize) -> Self {
+        let end = if (block + 1) * 8 < skips.len() {
+            read_int(skips, block * 2 + 3) as usize
+        } else {
+            blocks.len()
+        };
+        Self {
+            blocks: &blocks[..end],
+            pos: read_int(skips, block * 2 + 1) as usize,
+            left: (count - block * BLOCK_SIZE).min(BLOCK_SIZE),
+            last: None,
+            first: read_int(skips, block * 2),
+        }
+    }
+}
+
+impl<'a> Iterator for BlockIter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        if self.left == 0 {
+            return None;
+        }
+        self.left -= 1;
+        let v = match self.last {
+            None => self.first,
+            Some(last) => {
+                let next = read_varint(self.blocks, &mut self.pos)
+                    .ok()
+                    .and_then(|delta| last.checked_add(delta));
+                let Some(v) = next else {
+                    self.left = 0;
+                    return None;
+                };
+                v
+            }
+        };
+        self.last = Some(v);
+        Some(v)
+    }
+}
+
+pub struct PostingIter<'a>(IterState<'a>);
+
+enum IterState<'a> {
+    Array {
+        ids: &'a [u8],
+        i: usize,
+        count: usize,
+    },
+    Packed {
+        skips: &'a [u8],
+        nblocks: usize,
+        blocks: &'a [u8],
+        count: usize,
+        block: usize,
+        cur: Option<BlockIter<'a>>,
+    },
+    Bitmap {
+        base: u32,
+        bits: &'a [u8],
+        i: usize,
+    },
+}
+
+impl<'a> Iterator for PostingIter<'a> {
+    type Item = ID;
+
+    fn next(&mut self) -> Option<ID> {
+        match &mut self.0 {
+            IterState::Array { ids, i, count } => {
+                if *i >= *count {
+                    return None;
+                }
+                *i += 1;
+                Some(ID(read_int(ids, *i - 1)))
+            }
+            IterState::Packed {
+                skips,
+                nblocks,
+                blocks,
+                count,
+                block,
+                cur,
+            } => loop {
+                if let Some(v) = cur.as_mut().and_then(|it| it.next()) {
+                    return Some(ID(v));
+                }
+                if *block >= *nblocks {
+                    return None;
+                }
+                *cur = Some(BlockIter::new(skips, blocks, *block, *count));
+                *block += 1;
+            },
+            IterState::Bitmap { base, bits, i } => {
+                while *i < bits.len() * 8 {
+                    let byte = bits[*i / 8];
+                    if byte == 0 {
+                        *i = (*i / 8 + 1) * 8;
+                        continue;
+                    }
+                    let cur = *i;
+                    *i += 1;
+                    if byte & (1 << (cur % 8)) != 0 {
+                        return Some(ID(*base + cur as u32));
+                    }
+                }
+                None
+            }
+        }
+    }
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u32> {
+    let mut v = 0;
+    let mut shift = 0;
+    loop {
+        let b = *data
+            .get(*pos)
+            .ok_or(Error::Corrupted("tag file has a truncated block"))?;
+        *pos += 1;
+        v |= ((b & 0x7F) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Ok(v);
+        }
+        shift += 7;
+        if shift >= 32 {
+            return Err(Error::Corrupted("tag file has an invalid varint"));
+        }
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        out.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn encode_array(ids: &[u32]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + ids.len() * 4);
+    out.extend(u32::to_le_bytes(KIND_ARRAY));
+    out.extend(u32::to_le_bytes(ids.len() as u32));
+    for &id in ids {
+        out.extend(u32::to_le_bytes(id));
+    }
+    out
+}
+
+fn encode_packed(ids: &[u32]) -> Vec<u8> {
+    let nblocks = ids.len().div_ceil(BLOCK_SIZE);
+    let mut skips = Vec::with_capacity(nblocks * 8);
+    let mut blocks = vec![];
+    for block in ids.chunks(BLOCK_SIZE) {
+        skips.extend(u32::to_le_bytes(block[0]));
+        skips.extend(u32::to_le_bytes(blocks.len() as u32));
+        for w in block.windows(2) {
+            write_varint(&mut blocks, w[1] - w[0]);
+        }
+    }
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + 4 + skips.len() + blocks.len());
+    out.extend(u32::to_le_bytes(KIND_PACKED));
+    out.extend(u32::to_le_bytes(ids.len() as u32));
+    out.extend(u32::to_le_bytes(nblocks as u32));
+    out.extend(skips);
+    out.extend(blocks);
+    out
+}
+
+fn encode_bitmap(ids: &[u32]) -> Vec<u8> {
+    let base = ids[0];
+    let range = (ids[ids.len() - 1] - base) as usize + 1;
+    let mut bits = vec![0u8; range.div_ceil(8)];
+    for &id in ids {
+        let i = (id - base) as usize;
+        bits[i / 8] |= 1 << (i % 8);
+    }
+
+    let mut out = Vec::with_capacity(HEADER_SIZE + 4 + bits.len());
+    out.extend(u32::to_le_bytes(KIND_BITMAP));
+    out.extend(u32::to_le_bytes(ids.len() as u32));
+    out.extend(u32::to_le_bytes(base));
+    out.extend(bits);
+    out
+}
+
+/// Encodes a sorted and deduplicated list of IDs using the smallest representation
+pub fn encode(ids: &[u32]) -> Vec<u8> {
+    if ids.is_empty() {
+        return encode_array(ids);
+    }
+    let array_len = HEADER_SIZE + ids.len() * 4;
+    let range = (ids[ids.len() - 1] - ids[0]) as usize + 1;
+    let bitmap_len = HEADER_SIZE + 4 + range.div_ceil(8);
+    let packed = encode_packed(ids);
+
+    if bitmap_len < packed.len() && bitmap_len < array_len {
+        return encode_bitmap(ids);
+    }
+    if packed.len() < array_len {
+        return packed;
+    }
+    encode_array(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cases() -> Vec<Vec<u32>> {
+        vec![
+            vec![7],
+            vec![0, u32::MAX],
+            vec![1, 1 << 20, 1 << 28, u32::MAX - 1],
+            (0..1000).map(|x| x * 3 + 1).collect(),
+            (0..300).map(|x| x * x * 1000).collect(),
+        ]
+    }
+
+    fn check(bytes: &[u8], ids: &[u32]) {
+        let list = PostingList::new(bytes).unwrap();
+        assert_eq!(list.len(), ids.len());
+        assert_eq!(list.to_vec(), ids);
+        for &id in ids {
+            assert!(list.contains(ID(id)));
+            if id > 0 && !ids.contains(&(id - 1)) {
+                assert!(!list.contains(ID(id - 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        check(&encode_array(&[]), &[]);
+        check(&encode_packed(&[]), &[]);
+        check(&encode(&[]), &[]);
+        check(&[], &[]);
+        for ids in cases() {
+            check(&encode_array(&ids), &ids);
+            check(&encode_packed(&ids), &ids);
+            check(&encode(&ids), &ids);
+            if ids[ids.len() - 1] - ids[0] < 1 << 24 {
+                check(&encode_bitmap(&ids), &ids);
+            }
+        }
+    }
+
+    #[test]
+    fn corrupted_packed() {
+        let ids: Vec<u32> = (0..1000).map(|x| x * 3 + 1).collect();
+        let packed = encode_packed(&ids);
+
+        let mut bad = packed.clone();
+        bad[8..12].copy_from_slice(&u32::to_le_bytes(1));
+        assert!(PostingList::new(&bad).is_err());
+
+        let mut bad = packed.clone();
+        bad[16..20].copy_from_slice(&u32::to_le_bytes(u32::MAX));
+        assert!(PostingList::new(&bad).is_err());
+
+        assert!(PostingList::new(&packed[..packed.len() - 10]).is_err());
+        assert!(PostingList::new(&packed[..5]).is_err());
+
+        let mut bad = packed.clone();
+        let end = bad.len();
+        bad[end - 20..].fill(0xFF);
+        assert!(PostingList::new(&bad).is_err());
+
+        let mut bad = packed.clone();
+        let end = bad.len();
+        bad[end - 1] = 0;
+        assert!(PostingList::new(&bad).is_err());
+    }
+
+    #[test]
+    fn corrupted_array_and_bitmap() {
+        let array = encode_array(&[1, 2, 3]);
+        assert!(PostingList::new(&array[..array.len() - 2]).is_err());
+
+        let mut bitmap = encode_bitmap(&[1, 2, 3]);
+        bitmap[4..8].copy_from_slice(&u32::to_le_bytes(5));
+        assert!(PostingList::new(&bitmap).is_err());
+    }
+}
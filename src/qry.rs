@@ -1,159 +1,505 @@
-use crate::write::{data, get_allmap, get_datamap, getroot, value_from_off};
-use crate::{dnf, parse, TagName, Value, ID};
+use crate::error::Result;
+use crate::explain::Explain;
+use crate::hierarchy::{ancestors, is_under};
+use crate::page::{self, Cursor, Page};
+use crate::plan::{self, QueryPlan};
+use crate::postings::PostingList;
+use crate::tagname::{list_tags, tag_file};
+use crate::write::{data, get_allmap, get_datamap, get_offsetmap, open_tagmap};
+use crate::{dnf, eval, glob, parse, saved, TagName, Value, ID};
 use memmap2::Mmap;
-use std::collections::{BTreeMap, BTreeSet, HashSet};
-use std::fs::File;
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
 
 pub struct TagCtx {
-    mapped_tags: BTreeMap<TagName, Mmap>,
-    allmap: Mmap,
-    datamap: Mmap,
+    pub mapped_tags: BTreeMap<TagName, Mmap>,
+    pub allmap: Mmap,
+    pub offsetmap: Mmap,
+    pub datamap: Mmap,
 }
 
+/// A filter on the stored value string
 #[derive(Clone, Debug)]
-pub enum Expr {
-    Tag(TagName),
-    And(Box<Expr>, Box<Expr>),
-    Or(Box<Expr>, Box<Expr>),
-    Not(Box<Expr>),
+pub enum ValueMatch {
+    Glob(String),
+    Regex(Regex),
 }
 
-/// Disjunctive normal form
-/// List of ors of ands of tags with boolean = true or false
-#[derive(Debug)]
-pub struct DNF(pub Vec<Vec<(TagName, bool)>>);
+impl ValueMatch {
+    pub fn matches(&self, value: &str) -> bool {
+        match self {
+            ValueMatch::Glob(pattern) => glob::matches(pattern, value),
+            ValueMatch::Regex(re) => re.is_match(value),
+        }
+    }
 
-fn iter_data<'a>(datamap: &'a [u8]) -> impl Iterator<Item = Value> + 'a {
-    (0..datamap.len() / 256).map(move |off| value_from_off(datamap, off))
+    fn key(&self) -> (u8, &str) {
+        match self {
+            ValueMatch::Glob(pattern) => (0, pattern),
+            ValueMatch::Regex(re) => (1, re.as_str()),
+        }
+    }
 }
 
-fn iter_tagmap<'a>(map: &'a [u8]) -> impl Iterator<Item = ID> + 'a {
-    (0..map.len() / 4).map(move |off| ID(read_int(map, off)))
+// regexes are compared through their source so literals can be sorted and deduplicated
+impl PartialEq for ValueMatch {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
 }
 
-fn iter_tagmap_negall<'a>(allmap: &'a [u8], map: &'a [u8]) -> impl Iterator<Item = ID> + 'a {
-    iter_tagmap(allmap).filter(|x| !find(map, *x))
-}
+impl Eq for ValueMatch {}
 
-pub fn find(map: &[u8], needle: ID) -> bool {
-    let mut left = 0;
-    let mut right = map.len() / 4;
-    while left < right {
-        let middle = (left + right) / 2;
-        let v = read_int(map, middle);
-        if v == needle.0 {
-            return true;
-        }
-        if v > needle.0 {
-            right = middle;
-        } else {
-            left = middle + 1;
-        }
+impl PartialOrd for ValueMatch {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
-    false
 }
 
-fn execute_and(ctx: &TagCtx, mut needs: Vec<(TagName, bool)>, limit: usize) -> Vec<ID> {
-    needs.sort();
-    needs.dedup();
+impl Ord for ValueMatch {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
 
-    // detect p & !p
-    for w in needs.windows(2) {
-        if w[0].0 == w[1].0 && w[0].1 != w[1].1 {
-            return vec![];
+impl Display for ValueMatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValueMatch::Glob(pattern) => write!(
+                f,
+                "value:\"{}\"",
+                pattern.replace('\\', "\\\\").replace('"', "\\\"")
+            ),
+            // regexes keep their escapes as is
+            ValueMatch::Regex(re) => write!(f, "value~\"{}\"", re.as_str().replace('"', "\\\"")),
         }
     }
+}
 
-    let mut maps = Vec::with_capacity(needs.len());
-    for (tag, val) in needs {
-        let map = ctx.mapped_tags.get(&tag);
-        match map {
-            None => {
-                if val {
-                    return vec![];
-                } else {
-                    continue;
+/// A literal of the DNF, negated or not
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Atom {
+    Tag(TagName),
+    Value(ValueMatch),
+    /// At least N of the sets have the value, a set being a tag along with its descendants
+    AtLeast(usize, Vec<Vec<TagName>>),
+}
+
+impl Display for Atom {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Atom::Tag(tag) => fmt_tag(tag, f),
+            Atom::Value(p) => p.fmt(f),
+            Atom::AtLeast(n, sets) => {
+                write!(f, "atleast({}", n)?;
+                for set in sets {
+                    write!(f, ", ")?;
+                    for (i, tag) in set.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, "|")?;
+                        }
+                        fmt_tag(tag, f)?;
+                    }
                 }
+                write!(f, ")")
             }
-            Some(m) => maps.push((m, val)),
         }
     }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expr {
+    Tag(TagName),
+    /// Union of the tags matching the pattern
+    Glob(String),
+    Value(ValueMatch),
+    /// At least N of the tags or globs
+    AtLeast(usize, Vec<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    /// In exactly one of the sides, kept as is so chains of `^` do not blow up before evaluation
+    Xor(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+}
+
+/// Whether the tag can be written in a query without quotes
+fn is_bare(tag: &str) -> bool {
+    let mut chars = tag.chars();
+    chars.next().is_some_and(|c| c.is_alphanumeric())
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '/')
+        && !matches!(
+            tag.to_ascii_lowercase().as_str(),
+            "and" | "or" | "not" | "value" | "atleast"
+        )
+}
 
-    if maps.is_empty() {
-        return vec![];
+fn fmt_tag(tag: &TagName, f: &mut Formatter<'_>) -> std::fmt::Result {
+    if is_bare(&tag.0) {
+        write!(f, "{}", tag.0)
+    } else {
+        write!(
+            f,
+            "\"{}\"",
+            tag.0.replace('\\', "\\\\").replace('"', "\\\"")
+        )
     }
+}
 
-    let allsize = ctx.allmap.len() / 4;
-    let (i, _) = maps
-        .iter()
-        .enumerate()
-        .min_by_key(move |(_, (x, pos))| {
-            let mut l = x.len() / 4;
-            if !*pos {
-                l += allsize;
+/// Writes the expression back in the query language
+impl Display for Expr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Expr::Tag(tag) => fmt_tag(tag, f),
+            Expr::Glob(pattern) => write!(f, "{}", pattern),
+            Expr::Value(p) => p.fmt(f),
+            Expr::AtLeast(n, args) => {
+                write!(f, "atleast({}", n)?;
+                for arg in args {
+                    write!(f, ", {}", arg)?;
+                }
+                write!(f, ")")
             }
-            l
-        })
-        .unwrap();
+            Expr::Not(x) => write!(f, "!{}", x),
+            Expr::And(l, r) => write!(f, "({} & {})", l, r),
+            Expr::Or(l, r) => write!(f, "({} | {})", l, r),
+            Expr::Xor(l, r) => write!(f, "({} ^ {})", l, r),
+        }
+    }
+}
 
-    let (minm, pos) = maps.swap_remove(i);
+/// Disjunctive normal form
+/// List of ors of ands of atoms with boolean = true or false
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Debug)]
+pub struct DNF(pub Vec<Vec<(Atom, bool)>>);
 
-    let mut out = vec![];
-    if pos {
-        for val in iter_tagmap(minm) {
-            if out.len() >= limit {
-                return out;
+impl Display for DNF {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, and) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
             }
-
-            if maps.iter().all(|&(m, pos)| find(m, val) == pos) {
-                out.push(val);
+            let parens = self.0.len() > 1 && and.len() > 1;
+            if parens {
+                write!(f, "(")?;
+            }
+            for (j, (atom, positive)) in and.iter().enumerate() {
+                if j > 0 {
+                    write!(f, " & ")?;
+                }
+                if !positive {
+                    write!(f, "!")?;
+                }
+                atom.fmt(f)?;
+            }
+            if parens {
+                write!(f, ")")?;
             }
         }
-    } else {
-        for val in iter_tagmap_negall(&ctx.allmap, minm) {
-            if out.len() >= limit {
-                return out;
+        Ok(())
+    }
+}
+
+/// Tags of each argument of an expanded `atleast`, every argument being a tag or a union of tags.
+/// Repeated arguments are kept once so they are not counted twice.
+pub fn atleast_sets(args: &[Expr]) -> Vec<Vec<TagName>> {
+    fn collect(expr: &Expr, v: &mut Vec<TagName>) {
+        match expr {
+            Expr::Tag(x) => v.push(x.clone()),
+            Expr::Or(l, r) => {
+                collect(l, v);
+                collect(r, v);
             }
+            _ => unreachable!("atleast arguments should be expanded to tags"),
+        }
+    }
 
-            if maps.iter().all(|&(m, pos)| find(m, val) == pos) {
-                out.push(val);
+    let mut sets: Vec<Vec<TagName>> = args
+        .iter()
+        .map(|arg| {
+            let mut tags = vec![];
+            collect(arg, &mut tags);
+            tags.sort();
+            tags.dedup();
+            tags
+        })
+        .collect();
+    sets.sort();
+    sets.dedup();
+    sets
+}
+
+/// Counts for each ID how many of the sets have it and keeps those reaching `n`
+pub fn execute_atleast(ctx: &TagCtx, n: usize, sets: &[Vec<TagName>]) -> Result<Vec<ID>> {
+    if n == 0 {
+        return Ok(PostingList::new(&ctx.allmap)?.iter().collect());
+    }
+    let mut counts: BTreeMap<ID, usize> = BTreeMap::new();
+    for set in sets {
+        let mut ids = BTreeSet::new();
+        for tag in set {
+            if let Some(m) = ctx.mapped_tags.get(tag) {
+                ids.extend(PostingList::new(m)?.iter());
             }
         }
+        for id in ids {
+            *counts.entry(id).or_default() += 1;
+        }
     }
+    Ok(counts
+        .into_iter()
+        .filter(|&(_, count)| count >= n)
+        .map(|(id, _)| id)
+        .collect())
+}
 
-    out
+/// Whether the value of `id` satisfies every value predicate
+pub fn values_match(ctx: &TagCtx, id: ID, preds: &[(&ValueMatch, bool)]) -> Result<bool> {
+    if preds.is_empty() {
+        return Ok(true);
+    }
+    let value = match data(&ctx.offsetmap, &ctx.datamap, id)? {
+        Some(x) => x,
+        None => return Ok(false),
+    };
+    Ok(preds.iter().all(|(p, pos)| p.matches(&value.0) == *pos))
 }
 
-pub fn parse_and_execute(qry: &str, limit: usize) -> Vec<Value> {
-    let qry_expr = parse::parse_query(qry);
-    let qry_expr = match qry_expr {
+/// Past this many conjunctions the query is evaluated as a tree rather than in DNF.
+/// The DNF stops at the limit without materializing sets, but its size can explode.
+const MAX_DNF_CONJUNCTIONS: usize = 8;
+
+/// Parses the query and expands its tags and globs, None if the query is empty
+fn prepare_query(root: &mut PathBuf, qry: &str) -> Result<Option<Expr>> {
+    let qry_expr = match parse::parse_query(qry, &|name| saved::get(root, name))? {
+        None => return Ok(None),
+        Some(x) => x,
+    };
+    Ok(Some(expand_tags(qry_expr, &list_tags(root)?)))
+}
+
+pub fn parse_and_execute(root: &mut PathBuf, qry: &str, page: &Page) -> Result<Vec<Value>> {
+    Ok(explain(root, qry, page)?.0)
+}
+
+/// Runs the query, recording what was done along the way
+pub fn explain(root: &mut PathBuf, qry: &str, page: &Page) -> Result<(Vec<Value>, Explain)> {
+    let mut explain = Explain::new();
+    let ast = parse::parse_query(qry, &|name| saved::get(root, name))?;
+    explain.phase("parse");
+    explain.ast = ast.clone();
+
+    let qry_expr = match ast {
         None => {
-            let ctx = prepare_tags(&DNF(vec![]));
-            return iter_data(&ctx.datamap).take(limit).collect();
+            let ctx = prepare_tags(root, BTreeSet::new())?;
+            let all = PostingList::new(&ctx.allmap)?.iter();
+            let ids = match page.by_increasing_id() {
+                Some((k, after)) => all
+                    .filter(|&id| after.is_none_or(|a| id > a))
+                    .take(k)
+                    .collect(),
+                None => all.collect(),
+            };
+            let values = fetch_page(&ctx, ids, page, &mut explain)?;
+            return Ok((values, explain));
         }
-        Some(x) => x,
+        Some(x) => expand_tags(x, &list_tags(root)?),
     };
-    if std::env::var("DEBUG").is_ok() {
-        eprintln!("expr:    {:?}", qry_expr);
+    explain.phase("expand");
+    explain.expanded = Some(qry_expr.clone());
+
+    let conjunctions = dnf::dnf_size(&qry_expr);
+    if conjunctions > MAX_DNF_CONJUNCTIONS {
+        explain.plan = QueryPlan::Tree { conjunctions };
+        let ctx = prepare_tags(root, eval::expr_tags(&qry_expr))?;
+        let ids = eval::execute(&ctx, &qry_expr)?;
+        explain.phase("execute");
+        let values = fetch_page(&ctx, ids, page, &mut explain)?;
+        return Ok((values, explain));
     }
     let qry_cnf = dnf::to_dnf(qry_expr);
-    if std::env::var("DEBUG").is_ok() {
-        eprintln!("cnf: {:?}", qry_cnf);
+    explain.phase("to_dnf");
+    explain.dnf = Some(qry_cnf.clone());
+    let values = execute(root, qry_cnf, page, &mut explain)?;
+    Ok((values, explain))
+}
+
+/// Reads the values of the page out of the matching IDs
+fn fetch_page(
+    ctx: &TagCtx,
+    ids: Vec<ID>,
+    page: &Page,
+    explain: &mut Explain,
+) -> Result<Vec<Value>> {
+    let found = page::select(ctx, ids, page)?;
+    explain.phase("fetch");
+    explain.matches = found.len();
+    explain.next = found.last().map(|&(id, _)| Cursor(id));
+    Ok(found.into_iter().map(|(_, v)| v).collect())
+}
+
+/// Every value matching the query in ID order, along with the tags the query names
+pub fn matching_ids(root: &mut PathBuf, qry: &str) -> Result<(Vec<ID>, BTreeSet<TagName>)> {
+    let Some(ast) = parse::parse_query(qry, &|name| saved::get(root, name))? else {
+        let ctx = prepare_tags(root, BTreeSet::new())?;
+        return Ok((
+            PostingList::new(&ctx.allmap)?.iter().collect(),
+            BTreeSet::new(),
+        ));
+    };
+    let named = eval::expr_tags(&ast);
+    let qry_expr = expand_tags(ast, &list_tags(root)?);
+    let ctx = prepare_tags(root, eval::expr_tags(&qry_expr))?;
+    Ok((eval::execute(&ctx, &qry_expr)?, named))
+}
+
+/// Number of values matching the query
+pub fn count(root: &mut PathBuf, qry: &str) -> Result<usize> {
+    let qry_expr = prepare_query(root, qry)?;
+    let tags = qry_expr.as_ref().map(eval::expr_tags).unwrap_or_default();
+    let ctx = prepare_tags(root, tags)?;
+    match qry_expr {
+        None => Ok(PostingList::new(&ctx.allmap)?.len()),
+        Some(x) => eval::count(&ctx, &x),
     }
-    execute(qry_cnf, limit).collect()
 }
 
-pub fn execute(cnf: DNF, limit: usize) -> impl Iterator<Item = Value> {
-    let ctx = prepare_tags(&cnf);
+/// Plans the query without running it
+pub fn plan_query(root: &mut PathBuf, qry: &str) -> Result<QueryPlan> {
+    let qry_expr = match prepare_query(root, qry)? {
+        None => return Ok(QueryPlan::All),
+        Some(x) => x,
+    };
+    let conjunctions = dnf::dnf_size(&qry_expr);
+    if conjunctions > MAX_DNF_CONJUNCTIONS {
+        return Ok(QueryPlan::Tree { conjunctions });
+    }
+    let cnf = dnf::to_dnf(qry_expr);
+    let ctx = prepare_tags(root, dnf_tags(&cnf))?;
+    let mut branches = Vec::with_capacity(cnf.0.len());
+    for andqry in cnf.0 {
+        branches.push((plan::plan_and(&ctx, andqry)?.0, ()));
+    }
+    plan::order_branches(&mut branches);
+    Ok(QueryPlan::Dnf(
+        branches.into_iter().map(|(x, _)| x).collect(),
+    ))
+}
 
-    let mut ids: BTreeSet<ID> = Default::default();
+/// Replaces every tag by the union of itself and its descendants in the hierarchy,
+/// and the glob patterns by the union of the tags they match along with their descendants
+fn expand_tags(expr: Expr, tags: &[TagName]) -> Expr {
+    use Expr::*;
+    fn union(mut tags: impl Iterator<Item = TagName>) -> Option<Expr> {
+        let first = Tag(tags.next()?);
+        Some(tags.fold(first, |acc, t| Or(Box::new(acc), Box::new(Tag(t)))))
+    }
+
+    fn matching(expr: &Expr, tags: &[TagName]) -> Vec<TagName> {
+        match expr {
+            Tag(tag) => std::iter::once(tag.clone())
+                .chain(
+                    tags.iter()
+                        .filter(|t| t.0 != tag.0 && is_under(&t.0, &tag.0))
+                        .cloned(),
+                )
+                .collect(),
+            Glob(pattern) => tags
+                .iter()
+                .filter(|t| ancestors(&t.0).any(|a| glob::matches(pattern, a)))
+                .cloned()
+                .collect(),
+            _ => unreachable!("only tags and globs have matching tags"),
+        }
+    }
+
+    match expr {
+        Value(_) => expr,
+        Tag(_) => union(matching(&expr, tags).into_iter()).unwrap_or(expr),
+        Glob(ref pattern) => match union(matching(&expr, tags).into_iter()) {
+            Some(x) => x,
+            // nothing matches: `p & !p` is never true
+            None => {
+                let p = TagName(pattern.clone());
+                And(Box::new(Tag(p.clone())), Box::new(Not(Box::new(Tag(p)))))
+            }
+        },
+        // arguments matching nothing can never count so they are dropped
+        AtLeast(n, args) => AtLeast(
+            n,
+            args.iter()
+                .filter_map(|arg| union(matching(arg, tags).into_iter()))
+                .collect(),
+        ),
+        Not(x) => Not(Box::new(expand_tags(*x, tags))),
+        And(l, r) => And(
+            Box::new(expand_tags(*l, tags)),
+            Box::new(expand_tags(*r, tags)),
+        ),
+        Or(l, r) => Or(
+            Box::new(expand_tags(*l, tags)),
+            Box::new(expand_tags(*r, tags)),
+        ),
+        Xor(l, r) => Xor(
+            Box::new(expand_tags(*l, tags)),
+            Box::new(expand_tags(*r, tags)),
+        ),
+    }
+}
+
+/// Tags appearing in the DNF
+fn dnf_tags(cnf: &DNF) -> BTreeSet<TagName> {
+    let mut uniq_tags = BTreeSet::new();
+    for or in &cnf.0 {
+        for and in or {
+            match &and.0 {
+                Atom::Tag(tag) => {
+                    uniq_tags.insert(tag.clone());
+                }
+                Atom::AtLeast(_, sets) => uniq_tags.extend(sets.iter().flatten().cloned()),
+                Atom::Value(_) => {}
+            }
+        }
+    }
+    uniq_tags
+}
+
+pub fn execute(
+    root: &mut PathBuf,
+    cnf: DNF,
+    page: &Page,
+    explain: &mut Explain,
+) -> Result<Vec<Value>> {
+    let ctx = prepare_tags(root, dnf_tags(&cnf))?;
+
+    let mut branches = Vec::with_capacity(cnf.0.len());
     for andqry in cnf.0 {
-        if ids.len() >= limit {
-            break;
+        branches.push(plan::plan_and(&ctx, andqry)?);
+    }
+    plan::order_branches(&mut branches);
+    explain.phase("plan");
+
+    // the first k IDs of the union are among the first k IDs of each branch,
+    // and once k IDs are found the next branches only need the ones below the last of them.
+    // Other orders need every match.
+    let (limit, after) = page.by_increasing_id().unwrap_or((usize::MAX, None));
+    let mut ids: BTreeSet<ID> = Default::default();
+    for (plan, sets) in &mut branches {
+        let before = (ids.len() >= limit).then(|| ids.last().copied()).flatten();
+        let (found, stats) = plan::run_and(&ctx, plan, sets, limit, after, before)?;
+        plan.actual = Some(stats);
+        ids.extend(found);
+        while ids.len() > limit {
+            ids.pop_last();
         }
-        ids.extend(execute_and(&ctx, andqry, limit - ids.len()).into_iter());
     }
-    ids.into_iter().flat_map(move |id| data(&ctx.datamap, id))
+    explain.phase("execute");
+
+    let values = fetch_page(&ctx, ids.into_iter().collect(), page, explain)?;
+    explain.plan = QueryPlan::Dnf(branches.into_iter().map(|(x, _)| x).collect());
+    Ok(values)
 }
 
 pub fn read_int(map: &[u8], off: usize) -> u32 {
@@ -170,42 +516,52 @@ pub fn read_int(map: &[u8], off: usize) -> u32 {
 pub fn write_int(map: &mut [u8], off: usize, v: u32) {
     unsafe {
         let bytes = u32::to_le_bytes(v);
-        *map.get_unchecked_mut(off * 4 + 0) = *bytes.get_unchecked(0);
+        *map.get_unchecked_mut(off * 4) = *bytes.get_unchecked(0);
         *map.get_unchecked_mut(off * 4 + 1) = *bytes.get_unchecked(1);
         *map.get_unchecked_mut(off * 4 + 2) = *bytes.get_unchecked(2);
         *map.get_unchecked_mut(off * 4 + 3) = *bytes.get_unchecked(3);
     }
 }
 
-fn prepare_tags(cnf: &DNF) -> TagCtx {
-    let mut uniq_tags = BTreeSet::new();
-    for or in &cnf.0 {
-        for and in or {
-            uniq_tags.insert(and.0.clone());
-        }
-    }
-
-    let mut rootpath = getroot();
-    let allmap = get_allmap(&mut rootpath);
-    let (datamap, _) = get_datamap(&mut rootpath);
+/// Maps the files of the given tags along with the value files
+pub fn prepare_tags(root: &mut PathBuf, uniq_tags: BTreeSet<TagName>) -> Result<TagCtx> {
+    let allmap = get_allmap(root)?;
+    let (offsetmap, _) = get_offsetmap(root)?;
+    let (datamap, _) = get_datamap(root)?;
 
     let mut ctx = TagCtx {
         mapped_tags: BTreeMap::new(),
         allmap,
+        offsetmap,
         datamap,
     };
 
     for tag in uniq_tags {
-        rootpath.push(tag.0.clone());
-        let file = File::options().read(true).open(&rootpath);
-        let file = match file {
-            Ok(x) => x,
-            Err(_) => continue,
+        let name = match tag_file(&tag) {
+            Some(x) => x,
+            None => continue,
         };
-        rootpath.pop();
-        let mmap = unsafe { memmap2::Mmap::map(&file).expect("could not memmap file") };
-        ctx.mapped_tags.insert(tag, mmap);
+        if let Some(mmap) = open_tagmap(root, &name)? {
+            ctx.mapped_tags.insert(tag, mmap);
+        }
     }
 
-    ctx
+    Ok(ctx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(x: &str) -> Expr {
+        Expr::Tag(TagName(x.to_string()))
+    }
+
+    #[test]
+    fn repeated_atleast_arguments() {
+        let or = |l: &str, r: &str| Expr::Or(Box::new(tag(l)), Box::new(tag(r)));
+        let sets = atleast_sets(&[tag("live"), tag("live"), or("a", "b"), or("b", "a")]);
+        let names = |x: &[&str]| x.iter().map(|x| TagName(x.to_string())).collect::<Vec<_>>();
+        assert_eq!(sets, vec![names(&["a", "b"]), names(&["live"])]);
+    }
 }
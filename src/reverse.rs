@@ -0,0 +1,176 @@
+use crate::error::{io, Error, Result};
+use crate::postings::PostingList;
+use crate::qry::read_int;
+use crate::tagname::{list_tags, tag_file};
+use crate::write::{find_value, open_tagmap, write_atomic};
+use crate::{TagName, Value, ID};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+/// The tags of every value, the reverse of the tag files.
+/// Values are grouped by ranges of `BUCKET_SIZE` IDs, each range in its own file
+/// so setting a tag only rewrites a small file.
+///
+/// Layout: `[count: u32]([id: u32][ntags: u32]([len: u32][tag])*)*`, sorted by ID then tag.
+/// Stores created before the reverse index get it built from the tag files on first use.
+pub const REVERSE_DIR: &str = "reverse";
+const BUILD_DIR: &str = "reverse.tmp";
+const BUCKET_SIZE: u32 = 1024;
+
+type Bucket = BTreeMap<u32, BTreeSet<String>>;
+
+fn bucket_file(dir: &str, bucket: u32) -> String {
+    format!("{}/{}", dir, bucket)
+}
+
+fn decode(bytes: &[u8]) -> Result<Bucket> {
+    let corrupted = || Error::Corrupted("reverse index is truncated");
+    let mut pos = 0;
+    let int = |pos: &mut usize| -> Result<u32> {
+        let v = read_int(bytes.get(*pos..*pos + 4).ok_or_else(corrupted)?, 0);
+        *pos += 4;
+        Ok(v)
+    };
+    let mut bucket = Bucket::new();
+    for _ in 0..int(&mut pos)? {
+        let id = int(&mut pos)?;
+        let tags = bucket.entry(id).or_default();
+        for _ in 0..int(&mut pos)? {
+            let len = int(&mut pos)? as usize;
+            let tag = bytes.get(pos..pos + len).ok_or_else(corrupted)?;
+            pos += len;
+            tags.insert(
+                String::from_utf8(tag.to_vec())
+                    .map_err(|_| Error::Corrupted("reverse index is not valid utf-8"))?,
+            );
+        }
+    }
+    Ok(bucket)
+}
+
+fn encode(bucket: &Bucket) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend(u32::to_le_bytes(bucket.len() as u32));
+    for (id, tags) in bucket {
+        out.extend(u32::to_le_bytes(*id));
+        out.extend(u32::to_le_bytes(tags.len() as u32));
+        for tag in tags {
+            out.extend(u32::to_le_bytes(tag.len() as u32));
+            out.extend(tag.bytes());
+        }
+    }
+    out
+}
+
+fn load(root: &Path, bucket: u32) -> Result<Bucket> {
+    match std::fs::read(root.join(bucket_file(REVERSE_DIR, bucket))) {
+        Ok(bytes) => decode(&bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Bucket::new()),
+        Err(e) => Err(Error::Io {
+            context: "could not read reverse index",
+            source: e,
+        }),
+    }
+}
+
+fn save(root: &Path, bucket: u32, values: &Bucket) -> Result<()> {
+    let name = bucket_file(REVERSE_DIR, bucket);
+    if values.is_empty() {
+        return match std::fs::remove_file(root.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Io {
+                context: "could not delete reverse index bucket",
+                source: e,
+            }),
+            _ => Ok(()),
+        };
+    }
+    write_atomic(root, &name, &encode(values))
+}
+
+pub fn exists(root: &Path) -> bool {
+    root.join(REVERSE_DIR).is_dir()
+}
+
+/// Builds the reverse index from the tag files if the store does not have one yet.
+/// Must be called with an exclusive lock held.
+pub fn ensure(root: &mut PathBuf) -> Result<()> {
+    if exists(root) {
+        return Ok(());
+    }
+    let mut buckets: BTreeMap<u32, Bucket> = BTreeMap::new();
+    for tag in list_tags(root)? {
+        let map = match tag_file(&tag) {
+            Some(name) => open_tagmap(root, &name)?,
+            None => None,
+        };
+        let Some(map) = map else {
+            continue;
+        };
+        for id in PostingList::new(&map)?.iter() {
+            buckets
+                .entry(id.0 / BUCKET_SIZE)
+                .or_default()
+                .entry(id.0)
+                .or_default()
+                .insert(tag.0.clone());
+        }
+    }
+
+    // built aside and moved in place at once, so an interrupted build starts over
+    root.push(BUILD_DIR);
+    let _ = std::fs::remove_dir_all(&root);
+    let created = std::fs::create_dir(&root);
+    root.pop();
+    created.map_err(io("could not create reverse index dir"))?;
+    for (bucket, values) in &buckets {
+        write_atomic(root, &bucket_file(BUILD_DIR, *bucket), &encode(values))?;
+    }
+    std::fs::rename(root.join(BUILD_DIR), root.join(REVERSE_DIR))
+        .map_err(io("could not move reverse index in place"))
+}
+
+/// Records that the value `id` has the tag, building the reverse index first if needed
+pub fn add(root: &mut PathBuf, id: ID, tag: &TagName) -> Result<()> {
+    ensure(root)?;
+    let bucket = id.0 / BUCKET_SIZE;
+    let mut values = load(root, bucket)?;
+    if values.entry(id.0).or_default().insert(tag.0.clone()) {
+        save(root, bucket, &values)?;
+    }
+    Ok(())
+}
+
+/// Records that the value `id` no longer has the tag, building the reverse index first if needed
+pub fn remove(root: &mut PathBuf, id: ID, tag: &TagName) -> Result<()> {
+    ensure(root)?;
+    let bucket = id.0 / BUCKET_SIZE;
+    let mut values = load(root, bucket)?;
+    let Some(tags) = values.get_mut(&id.0) else {
+        return Ok(());
+    };
+    if !tags.remove(&tag.0) {
+        return Ok(());
+    }
+    if tags.is_empty() {
+        values.remove(&id.0);
+    }
+    save(root, bucket, &values)
+}
+
+/// The tags of the value, sorted, None if it is not in the store
+pub fn value_tags(root: &mut PathBuf, value: &Value) -> Result<Option<Vec<TagName>>> {
+    match find_value(root, value)? {
+        Some(id) => tags_of(root, id).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn tags_of(root: &Path, id: ID) -> Result<Vec<TagName>> {
+    let mut values = load(root, id.0 / BUCKET_SIZE)?;
+    Ok(values
+        .remove(&id.0)
+        .unwrap_or_default()
+        .into_iter()
+        .map(TagName)
+        .collect())
+}
@@ -0,0 +1,73 @@
+use crate::error::{io, Error, Result};
+use crate::parse::{self, is_saved_name};
+use crate::tagname::{file_name, list_names, remove_name, save_name};
+use crate::write::write_atomic;
+use std::path::{Path, PathBuf};
+
+/// Saved queries are kept as their source, one file per name named like tag files
+pub const SAVED_DIR: &str = "saved";
+
+fn saved_file(name: &str) -> Result<String> {
+    match file_name(name) {
+        Some(x) if is_saved_name(name) => Ok(format!("{}/{}", SAVED_DIR, x)),
+        _ => Err(Error::InvalidSavedName(name.to_string())),
+    }
+}
+
+/// The source of the saved query, None if there is none by that name
+pub fn get(root: &Path, name: &str) -> Result<Option<String>> {
+    let Ok(file) = saved_file(name) else {
+        return Ok(None);
+    };
+    match std::fs::read_to_string(root.join(file)) {
+        Ok(x) => Ok(Some(x)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(Error::Io {
+            context: "could not read saved query",
+            source: e,
+        }),
+    }
+}
+
+/// Saves the query under `name`, replacing the previous one.
+/// The query must parse, and may reference other saved queries as long as they do not reference it back.
+pub fn save(root: &mut PathBuf, name: &str, qry: &str) -> Result<()> {
+    let file = saved_file(name)?;
+    let expr = parse::parse_saved(name, qry, &|n| get(root, n))?;
+    if expr.is_none() {
+        return Err(Error::EmptySavedQuery);
+    }
+    root.push(SAVED_DIR);
+    let created = std::fs::create_dir_all(&root);
+    root.pop();
+    created.map_err(io("could not create saved queries dir"))?;
+    save_name(root, &file, name)?;
+    write_atomic(root, &file, qry.as_bytes())
+}
+
+/// Deletes the saved query, returns whether there was one
+pub fn delete(root: &Path, name: &str) -> Result<bool> {
+    let Ok(file) = saved_file(name) else {
+        return Ok(false);
+    };
+    match std::fs::remove_file(root.join(&file)) {
+        Ok(()) => remove_name(root, &file).map(|_| true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(Error::Io {
+            context: "could not delete saved query",
+            source: e,
+        }),
+    }
+}
+
+/// Every saved query with its source, sorted by name
+pub fn list(root: &mut PathBuf) -> Result<Vec<(String, String)>> {
+    let mut saved = vec![];
+    for name in list_names(root, SAVED_DIR)? {
+        if let Some(src) = get(root, &name)? {
+            saved.push((name, src));
+        }
+    }
+    saved.sort();
+    Ok(saved)
+}
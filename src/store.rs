@@ -0,0 +1,196 @@
+use crate::error::{io, Error, Result};
+use crate::explain::Explain;
+use crate::lock::{lock, lock_any_format, LockKind, StoreLock};
+use crate::page::Page;
+use crate::plan::QueryPlan;
+use crate::tagname;
+use crate::write::{self, create_store, getroot, STORE_DIR};
+use crate::{facets, format, hierarchy, qry, reverse, saved, TagName, Value};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A tag store on disk.
+/// Every operation locks the store for its duration so several processes can share it.
+pub struct Store {
+    root: PathBuf,
+    lock_timeout: Duration,
+    discarded_journal: Cell<Option<Error>>,
+}
+
+impl Store {
+    /// Opens the store at `path`, creating it if needed
+    pub fn open(path: impl Into<PathBuf>) -> Result<Store> {
+        Ok(Store {
+            root: create_store(path.into())?,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            discarded_journal: Cell::new(None),
+        })
+    }
+
+    /// Opens the store from `RTAG_HOME`, the nearest `.rtag` directory up from
+    /// the current directory, or `$HOME/.rtag/`, in that order
+    pub fn discover() -> Result<Store> {
+        Ok(Store {
+            root: getroot()?,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+            discarded_journal: Cell::new(None),
+        })
+    }
+
+    /// Creates a project-local store in `dir`
+    pub fn init(dir: impl Into<PathBuf>) -> Result<Store> {
+        let mut root = dir.into();
+        root.push(STORE_DIR);
+        Store::open(root)
+    }
+
+    /// How long to wait for other processes to release the store, defaults to 10 seconds
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Why the journal of a write interrupted by another process was discarded
+    /// since the last call, if it was. Such a write never started, none of it is applied.
+    pub fn take_discarded_journal(&self) -> Option<Error> {
+        self.discarded_journal.take()
+    }
+
+    fn lock(&self, kind: LockKind) -> Result<(PathBuf, StoreLock)> {
+        let mut root = self.root.clone();
+        let mut lock = lock(&mut root, kind, self.lock_timeout)?;
+        if let Some(e) = lock.discarded_journal.take() {
+            self.discarded_journal.set(Some(e));
+        }
+        Ok((root, lock))
+    }
+
+    pub fn add_tag(&self, tag: &TagName, value: &Value) -> Result<()> {
+        let (mut root, _lock) = self.lock(LockKind::Exclusive)?;
+        write::add_tag(&mut root, tag, value)
+    }
+
+    pub fn del_tag(&self, tag: &TagName, value: &Value) -> Result<()> {
+        let (mut root, _lock) = self.lock(LockKind::Exclusive)?;
+        write::del_tag(&mut root, tag, value)
+    }
+
+    /// Returns the first `limit` values matching the query
+    pub fn query(&self, qry: &str, limit: usize) -> Result<Vec<Value>> {
+        self.query_page(qry, &Page::new(limit))
+    }
+
+    /// Returns one page of the values matching the query
+    pub fn query_page(&self, qry: &str, page: &Page) -> Result<Vec<Value>> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        qry::parse_and_execute(&mut root, qry, page)
+    }
+
+    /// Runs the query like `query_page`, also returning how it was run
+    /// along with the cursor of the next page
+    pub fn explain(&self, qry: &str, page: &Page) -> Result<(Vec<Value>, Explain)> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        qry::explain(&mut root, qry, page)
+    }
+
+    /// Number of values matching the query, computed from the tag files alone
+    /// unless the query filters on values
+    pub fn count(&self, qry: &str) -> Result<usize> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        qry::count(&mut root, qry)
+    }
+
+    /// The tags found among the values matching the query with how many values have each,
+    /// most frequent first, leaving out the tags the query names.
+    /// With `prefix`, only the tags starting with it are counted.
+    pub fn facets(
+        &self,
+        qry: &str,
+        prefix: Option<&str>,
+        limit: usize,
+    ) -> Result<Vec<(TagName, usize)>> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        facets::facets(&mut root, qry, prefix, limit)
+    }
+
+    /// How the query would be executed, without running it
+    pub fn plan(&self, qry: &str) -> Result<QueryPlan> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        qry::plan_query(&mut root, qry)
+    }
+
+    /// Saves the query so other queries can reference it as `@name`, replacing any previous one
+    pub fn save_query(&self, name: &str, qry: &str) -> Result<()> {
+        let (mut root, _lock) = self.lock(LockKind::Exclusive)?;
+        saved::save(&mut root, name, qry)
+    }
+
+    /// The source of the saved query, None if there is none by that name
+    pub fn saved_query(&self, name: &str) -> Result<Option<String>> {
+        let (root, _lock) = self.lock(LockKind::Shared)?;
+        saved::get(&root, name)
+    }
+
+    /// Every saved query with its source, sorted by name
+    pub fn saved_queries(&self) -> Result<Vec<(String, String)>> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        saved::list(&mut root)
+    }
+
+    /// Deletes the saved query, returns whether there was one.
+    /// Queries referencing it fail to parse until it is saved again.
+    pub fn delete_saved_query(&self, name: &str) -> Result<bool> {
+        let (root, _lock) = self.lock(LockKind::Exclusive)?;
+        saved::delete(&root, name)
+    }
+
+    /// The tags set on the value, sorted, None if the value is not in the store
+    pub fn value_tags(&self, value: &Value) -> Result<Option<Vec<TagName>>> {
+        {
+            let (mut root, _lock) = self.lock(LockKind::Shared)?;
+            if reverse::exists(&root) {
+                return reverse::value_tags(&mut root, value);
+            }
+        }
+        // stores created before the reverse index need it built first
+        let (mut root, _lock) = self.lock(LockKind::Exclusive)?;
+        reverse::ensure(&mut root)?;
+        reverse::value_tags(&mut root, value)
+    }
+
+    pub fn tags(&self) -> Result<Vec<TagName>> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        tagname::list_tags(&mut root)
+    }
+
+    /// Every node of the tag hierarchy with how many values are under it,
+    /// parents come right before their children
+    pub fn tree(&self) -> Result<Vec<(TagName, usize)>> {
+        let (mut root, _lock) = self.lock(LockKind::Shared)?;
+        hierarchy::tree(&mut root)
+    }
+
+    /// Upgrades a store written by an older rtag to the current layout,
+    /// returns the format version it had
+    pub fn migrate(&self) -> Result<u32> {
+        let mut root = self.root.clone();
+        let _lock = lock_any_format(&mut root, self.lock_timeout)?;
+        format::migrate(&mut root)
+    }
+
+    /// Removes all tags and values along with the store itself, whatever its layout
+    pub fn clean(self) -> Result<()> {
+        let mut root = self.root.clone();
+        let lock = lock_any_format(&mut root, self.lock_timeout)?;
+        std::fs::remove_dir_all(&root).map_err(io("failed cleaning"))?;
+        drop(lock);
+        Ok(())
+    }
+}
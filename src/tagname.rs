@@ -0,0 +1,173 @@
+use crate::error::{io, Error, Result};
+use crate::write::write_atomic;
+use crate::TagName;
+use std::path::{Path, PathBuf};
+
+/// Tag files live in their own directory so they never collide with the store's internal files
+pub const TAGS_DIR: &str = "tags";
+
+/// Most filesystems refuse longer file names
+const MAX_FILENAME_LENGTH: usize = 255;
+
+/// Names too long once encoded are stored under a hash of the name instead,
+/// starting with `~` so they never collide with encoded names,
+/// and the name itself is kept in a file next to it with the `.name` extension
+const HASHED_PREFIX: &str = "~";
+const NAME_EXTENSION: &str = ".name";
+
+fn is_kept(b: u8) -> bool {
+    b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-'
+}
+
+/// Reversibly encodes a name into a file name.
+/// Bytes other than ascii lowercase letters, digits, `_` and `-` are percent encoded,
+/// so `/`, `.` or `..` can never escape the directory,
+/// and names differing by case do not collide on case-insensitive filesystems.
+/// Returns None if the name is empty or too long to be stored.
+fn encode(name: &str) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    let mut out = String::with_capacity(name.len());
+    for b in name.bytes() {
+        if is_kept(b) {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    if out.len() > MAX_FILENAME_LENGTH {
+        return None;
+    }
+    Some(out)
+}
+
+/// Decodes a file name produced by `encode`, returns None for foreign files
+fn decode(name: &str) -> Option<String> {
+    decode_with(name, is_kept)
+}
+
+/// Decodes a file name of format version 2, which kept ascii uppercase letters as is
+pub fn decode_v2(name: &str) -> Option<String> {
+    decode_with(name, |b| is_kept(b) || b.is_ascii_uppercase())
+}
+
+fn decode_with(name: &str, is_kept: impl Fn(u8) -> bool) -> Option<String> {
+    let bytes = name.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = name.get(i + 1..i + 3)?;
+                out.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b if is_kept(b) => {
+                out.push(b);
+                i += 1;
+            }
+            _ => return None,
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+/// 128 bits FNV-1a, stable across rust versions since file names are persisted
+fn hash_name(name: &str) -> u128 {
+    let mut h: u128 = 0x6c62272e07bb014262b821756295c58d;
+    for b in name.bytes() {
+        h ^= b as u128;
+        h = h.wrapping_mul(0x0000000001000000000000000000013B);
+    }
+    h
+}
+
+/// File name of `name` in its directory, None if the name is empty
+pub fn file_name(name: &str) -> Option<String> {
+    if name.is_empty() {
+        return None;
+    }
+    Some(encode(name).unwrap_or_else(|| format!("{}{:032x}", HASHED_PREFIX, hash_name(name))))
+}
+
+/// Path of the file holding the name of `file`, None if `file` is named after it
+pub fn name_file(file: &str) -> Option<String> {
+    let base = file.rsplit('/').next().unwrap_or(file);
+    base.starts_with(HASHED_PREFIX)
+        .then(|| format!("{}{}", file, NAME_EXTENSION))
+}
+
+/// Records the name of `file` when it cannot be told from its file name.
+/// Must be called before `file` is created, so it can always be listed.
+pub fn save_name(root: &Path, file: &str, name: &str) -> Result<()> {
+    match name_file(file) {
+        Some(x) if !root.join(&x).exists() => write_atomic(root, &x, name.as_bytes()),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the name of `file` along with it
+pub fn remove_name(root: &Path, file: &str) -> Result<()> {
+    let Some(x) = name_file(file) else {
+        return Ok(());
+    };
+    match std::fs::remove_file(root.join(x)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Io {
+            context: "could not delete name file",
+            source: e,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Path of the tag file relative to the store root, None if the tag is empty
+pub fn tag_file(tag: &TagName) -> Option<String> {
+    Some(format!("{}/{}", TAGS_DIR, file_name(&tag.0)?))
+}
+
+/// The names of the files of `dir`, no names if it does not exist
+pub fn list_names(root: &mut PathBuf, dir: &str) -> Result<Vec<String>> {
+    root.push(dir);
+    let entries = std::fs::read_dir(&root);
+    root.pop();
+    let entries = match entries {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => {
+            return Err(Error::Io {
+                context: "cannot read dir",
+                source: e,
+            })
+        }
+    };
+    let mut names = vec![];
+    for file in entries {
+        let file = file.map_err(io("cannot read dir"))?;
+        let Some(file) = file.file_name().to_str().map(|x| x.to_string()) else {
+            continue;
+        };
+        if let Some(name) = decode(&file) {
+            names.push(name);
+            continue;
+        }
+        if file.ends_with(NAME_EXTENSION) {
+            continue;
+        }
+        let Some(name_file) = name_file(&format!("{}/{}", dir, file)) else {
+            continue;
+        };
+        if let Ok(name) = std::fs::read_to_string(root.join(name_file)) {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Lists the tags of the store
+pub fn list_tags(root: &mut PathBuf) -> Result<Vec<TagName>> {
+    Ok(list_names(root, TAGS_DIR)?
+        .into_iter()
+        .map(TagName)
+        .collect())
+}
@@ -1,53 +1,171 @@
-use crate::qry::{find, read_int, write_int};
+use crate::error::{io, Error, Result};
+use crate::index::{self, Index};
+use crate::journal::{self, Op};
+use crate::postings::{self, PostingList};
+use crate::qry::read_int;
+use crate::reverse;
+use crate::tagname::{remove_name, save_name, tag_file};
 use crate::{TagName, Value, ID};
 use memmap2::Mmap;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-pub const MAX_VALUE_LENGTH: usize = 251;
+pub const MAX_VALUE_LENGTH: usize = u32::MAX as usize;
 
-pub fn getroot() -> PathBuf {
-    let home = std::env::var("HOME").expect("HOME is not defined in env");
+pub const STORE_DIR: &str = ".rtag";
 
-    let mut rootpath = PathBuf::from(home);
-    rootpath.push(".rtag/");
+/// Finds the nearest `.rtag` directory in the current directory or its ancestors
+fn find_local_store() -> Option<PathBuf> {
+    let mut dir = std::env::current_dir().ok()?;
+    loop {
+        dir.push(STORE_DIR);
+        if dir.is_dir() {
+            return Some(dir);
+        }
+        dir.pop();
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+
+/// Resolves the store location: `RTAG_HOME`,
+/// then the nearest `.rtag` directory up from the current directory, then `$HOME/.rtag/`
+pub fn getroot() -> Result<PathBuf> {
+    let rootpath = if let Some(home) = std::env::var_os("RTAG_HOME") {
+        PathBuf::from(home)
+    } else if let Some(local) = find_local_store() {
+        local
+    } else {
+        let home = std::env::var_os("HOME").ok_or(Error::NoStore)?;
+        let mut rootpath = PathBuf::from(home);
+        rootpath.push(STORE_DIR);
+        rootpath
+    };
+    create_store(rootpath)
+}
+
+/// Creates the store directory if needed, what goes in it is only created
+/// under lock once its layout is known
+pub fn create_store(rootpath: PathBuf) -> Result<PathBuf> {
+    std::fs::create_dir_all(&rootpath).map_err(io("failed creating rtag dir"))?;
+    Ok(rootpath)
+}
+
+fn offsetfile_len(root: &mut PathBuf) -> u64 {
+    root.push("__offsets");
+    let len = std::fs::metadata(&root).map(|m| m.len()).unwrap_or(0);
+    root.pop();
+    len
+}
 
-    std::fs::create_dir_all(&rootpath)
-        .expect(&*format!("failed creating rtag dir at {:?}", &rootpath));
-    rootpath
+/// Whether rtag was interrupted while writing to the store
+pub fn needs_recovery(root: &mut PathBuf) -> bool {
+    root.push("__journal");
+    let has_journal = root.exists();
+    root.pop();
+    has_journal || !offsetfile_len(root).is_multiple_of((OFFSET_INTS * 4) as u64)
 }
 
-pub fn get_allmap(root: &mut PathBuf) -> Mmap {
+/// Brings the store back to a consistent state after rtag was interrupted:
+/// drops a partially written offset entry and replays the journaled operation.
+/// A journal that cannot be decoded was never fully written, so its operation never started:
+/// it is removed and why it could not be decoded is returned.
+/// Must be called with an exclusive lock held.
+pub fn recover(root: &mut PathBuf) -> Result<Option<Error>> {
+    let len = offsetfile_len(root);
+    let entry_size = (OFFSET_INTS * 4) as u64;
+    if !len.is_multiple_of(entry_size) {
+        let (_, offsetfile) = get_offsetmap(root)?;
+        offsetfile
+            .set_len(len - len % entry_size)
+            .map_err(io("could not truncate offsetfile"))?;
+    }
+
+    let mut discarded = None;
+    match journal::pending(root) {
+        Err(e @ Error::Corrupted(_)) => discarded = Some(e),
+        Err(e) => return Err(e),
+        Ok(None) => return Ok(None),
+        Ok(Some(Op::AddTag(tag, value))) => {
+            apply_add_tag(root, &tag, &checked_tag_file(&tag)?, &value)?
+        }
+        Ok(Some(Op::DelTag(tag, value))) => {
+            apply_del_tag(root, &tag, &checked_tag_file(&tag)?, &value)?
+        }
+    }
+    journal::commit(root)?;
+    Ok(discarded)
+}
+
+/// Replaces the file atomically so readers and crashes never observe a half written file.
+/// The content reaches the disk before the rename, and the rename before returning.
+pub fn write_atomic(root: &Path, name: &str, bytes: &[u8]) -> Result<()> {
+    let tmp = root.join("__tmp");
+    let mut file = File::create(&tmp).map_err(io("could not create temporary file"))?;
+    file.write_all(bytes)
+        .map_err(io("could not write temporary file"))?;
+    file.sync_all()
+        .map_err(io("could not sync temporary file"))?;
+    std::fs::rename(&tmp, root.join(name)).map_err(io("could not replace file"))?;
+    let dir = root.join(name);
+    let dir = dir.parent().unwrap_or(root);
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .map_err(io("could not sync store dir"))
+}
+
+pub fn get_allmap(root: &mut PathBuf) -> Result<Mmap> {
     root.push("__all");
     let allfile = File::options()
         .create(true)
+        .truncate(false)
         .write(true)
         .read(true)
-        .open(&root)
-        .expect("could not open allfile");
+        .open(&root);
     root.pop();
+    let allfile = allfile.map_err(io("could not open allfile"))?;
+
+    unsafe { memmap2::Mmap::map(&allfile).map_err(io("could not memmap all file")) }
+}
+
+/// Values are stored back to back in `__data`.
+/// `__offsets` holds one entry per value: `[start lo: u32][start hi: u32][len: u32][id: u32]`
+const OFFSET_INTS: usize = 4;
+
+pub fn n_values(offsetmap: &[u8]) -> usize {
+    offsetmap.len() / (OFFSET_INTS * 4)
+}
 
-    unsafe { memmap2::Mmap::map(&allfile).expect("could not memmap all file") }
+fn id_from_off(offsetmap: &[u8], off: usize) -> ID {
+    ID(read_int(offsetmap, off * OFFSET_INTS + 3))
 }
 
-pub fn value_from_off(datamap: &[u8], off: usize) -> Value {
-    let bytes = &datamap[off * 256..off * 256 + MAX_VALUE_LENGTH + 1];
-    let len = bytes[0] as usize;
-    Value(
-        String::from_utf8(bytes[1..len + 1].iter().copied().collect()).expect("data is corrupted"),
-    )
+fn record<'a>(offsetmap: &[u8], datamap: &'a [u8], off: usize) -> Result<&'a [u8]> {
+    let start = read_int(offsetmap, off * OFFSET_INTS) as usize
+        | (read_int(offsetmap, off * OFFSET_INTS + 1) as usize) << 32;
+    let len = read_int(offsetmap, off * OFFSET_INTS + 2) as usize;
+    datamap
+        .get(start..start + len)
+        .ok_or(Error::Corrupted("value offset is out of the data file"))
+}
+
+pub fn value_from_off(offsetmap: &[u8], datamap: &[u8], off: usize) -> Result<Value> {
+    String::from_utf8(record(offsetmap, datamap, off)?.to_vec())
+        .map(Value)
+        .map_err(|_| Error::Corrupted("value is not valid utf-8"))
 }
 
 // Finds the ID with a corresponding data if it exists
-pub fn data(datamap: &[u8], needle: ID) -> Option<Value> {
+pub fn data(offsetmap: &[u8], datamap: &[u8], needle: ID) -> Result<Option<Value>> {
     let mut left = 0;
-    let mut right = datamap.len() / 256;
+    let mut right = n_values(offsetmap);
     while left < right {
         let middle = (left + right) / 2;
-        let v = read_int(datamap, middle * 64 + 63);
+        let v = id_from_off(offsetmap, middle).0;
         if v == needle.0 {
-            return Some(value_from_off(datamap, middle));
+            return value_from_off(offsetmap, datamap, middle).map(Some);
         }
         if v > needle.0 {
             right = middle;
@@ -55,182 +173,307 @@ pub fn data(datamap: &[u8], needle: ID) -> Option<Value> {
             left = middle + 1;
         }
     }
-    None
+    Ok(None)
 }
 
-// Finds the ID with a corresponding data if it exists
-pub fn search_data(data: &[u8], needle: &[u8]) -> Option<ID> {
-    assert_eq!(needle.len(), MAX_VALUE_LENGTH + 1);
+pub fn open_index(root: &mut PathBuf, offsetmap: &[u8], datamap: &[u8]) -> Result<Index> {
+    Index::open(root, n_values(offsetmap), |off| {
+        record(offsetmap, datamap, off)
+    })
+}
 
-    for i in 0..data.len() / 256 {
-        let v = &data[i * 256..i * 256 + MAX_VALUE_LENGTH + 1];
-        if v == needle {
-            return Some(ID(read_int(data, i * 64 + 63)));
-        }
-    }
-    None
+// Finds the ID with a corresponding data if it exists
+pub fn search_data(
+    index: &Index,
+    offsetmap: &[u8],
+    datamap: &[u8],
+    needle: &[u8],
+) -> Result<Option<ID>> {
+    Ok(index
+        .lookup(needle, |off| record(offsetmap, datamap, off))?
+        .map(|off| id_from_off(offsetmap, off)))
 }
 
-pub fn get_datamap(root: &mut PathBuf) -> (Mmap, File) {
+pub fn get_datamap(root: &mut PathBuf) -> Result<(Mmap, File)> {
     root.push("__data");
     let datafile = File::options()
         .create(true)
+        .truncate(false)
         .write(true)
         .read(true)
-        .open(&root)
-        .expect("could not open datafile");
+        .open(&root);
     root.pop();
+    let datafile = datafile.map_err(io("could not open datafile"))?;
 
-    (
-        unsafe { memmap2::Mmap::map(&datafile).expect("could not memmap data file") },
+    Ok((
+        unsafe { memmap2::Mmap::map(&datafile).map_err(io("could not memmap data file"))? },
         datafile,
-    )
+    ))
 }
 
-fn prepare_data_needle(value: &Value) -> Vec<u8> {
-    let mut bytes = vec![value.0.len() as u8];
-    bytes.extend(value.0.bytes());
-    bytes.extend((0..MAX_VALUE_LENGTH + 1 - bytes.len()).map(|_| 0));
-    bytes
+pub fn get_offsetmap(root: &mut PathBuf) -> Result<(Mmap, File)> {
+    root.push("__offsets");
+    let offsetfile = File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .read(true)
+        .open(&root);
+    root.pop();
+    let offsetfile = offsetfile.map_err(io("could not open offsetfile"))?;
+
+    Ok((
+        unsafe { memmap2::Mmap::map(&offsetfile).map_err(io("could not memmap offset file"))? },
+        offsetfile,
+    ))
 }
 
-pub fn insert_data(root: &mut PathBuf, value: &Value) -> (ID, bool) {
-    let (data, mut datafile) = get_datamap(root);
-    let data = data.make_mut().expect("could not make mat mup");
+pub fn insert_data(root: &mut PathBuf, value: &Value) -> Result<(ID, bool)> {
+    let (data, mut datafile) = get_datamap(root)?;
+    let (offsets, mut offsetfile) = get_offsetmap(root)?;
 
-    if data.len() % 256 != 0 {
-        panic!("data is corrupted")
+    if offsets.len() % (OFFSET_INTS * 4) != 0 {
+        return Err(Error::Corrupted("offset file has a partial entry"));
     }
     if value.0.len() > MAX_VALUE_LENGTH {
-        panic!("value too long: max is {} bytes", MAX_VALUE_LENGTH)
+        return Err(Error::ValueTooLong(value.0.len()));
     }
-    let mut bytes = prepare_data_needle(value);
-    if let Some(id) = search_data(&data, &bytes) {
-        return (id, false);
+    let bytes = value.0.as_bytes();
+    let mut index = open_index(root, &offsets, &data)?;
+    if let Some(id) = search_data(&index, &offsets, &data, bytes)? {
+        return Ok((id, false));
     }
 
-    let newid = if data.len() == 0 {
+    let n = n_values(&offsets);
+    let newid = if n == 0 {
         1
     } else {
-        read_int(&data, data.len() / 4 - 1) + 1
+        id_from_off(&offsets, n - 1).0 + 1
     };
-    drop(data);
 
-    datafile
+    let start = datafile
         .seek(SeekFrom::End(0))
-        .expect("failed seeking to end");
-    bytes.extend(u32::to_le_bytes(newid));
-    assert_eq!(bytes.len(), 256);
+        .map_err(io("failed seeking to end"))?;
     datafile
-        .write_all(&bytes)
-        .expect("failed adding new id to data");
+        .write_all(bytes)
+        .map_err(io("failed adding new value to data"))?;
+    // the value must be on disk before an offset entry points to it
+    datafile.sync_data().map_err(io("failed syncing data"))?;
+
+    let mut entry = Vec::with_capacity(OFFSET_INTS * 4);
+    entry.extend(u64::to_le_bytes(start));
+    entry.extend(u32::to_le_bytes(bytes.len() as u32));
+    entry.extend(u32::to_le_bytes(newid));
+    offsetfile
+        .seek(SeekFrom::End(0))
+        .map_err(io("failed seeking to end"))?;
+    offsetfile
+        .write_all(&entry)
+        .map_err(io("failed adding new id to offsets"))?;
+    offsetfile
+        .sync_data()
+        .map_err(io("failed syncing offsets"))?;
+
+    index.insert(bytes, n, |off| record(&offsets, &data, off))?;
 
-    return (ID(newid), true);
+    Ok((ID(newid), true))
 }
 
-pub fn insert_tag_in_map(root: &mut PathBuf, name: &str, id: ID) {
-    root.push(name);
-    let mut file = File::options()
-        .create(true)
-        .write(true)
-        .read(true)
-        .open(&root)
-        .expect("could not open allfile");
-    root.pop();
+pub fn open_tagmap(root: &Path, name: &str) -> Result<Option<Mmap>> {
+    let file = match File::options().read(true).open(root.join(name)) {
+        Ok(x) => x,
+        Err(_) => return Ok(None),
+    };
+    unsafe { memmap2::Mmap::map(&file) }
+        .map(Some)
+        .map_err(io("could not memmap tag file"))
+}
 
-    {
-        let map = unsafe { memmap2::Mmap::map(&file).expect("could not memmap all file") };
+fn write_tagmap(root: &Path, name: &str, ids: &[u32]) -> Result<()> {
+    if ids.is_empty() {
+        std::fs::remove_file(root.join(name)).map_err(io("could not delete tagmap"))?;
+        return remove_name(root, name);
+    }
+    write_atomic(root, name, &postings::encode(ids))
+}
 
-        if find(&map, id) {
-            return;
+pub fn insert_tag_in_map(root: &Path, name: &str, id: ID) -> Result<()> {
+    let mut ids = match open_tagmap(root, name)? {
+        Some(map) => {
+            let list = PostingList::new(&map)?;
+            if list.contains(id) {
+                return Ok(());
+            }
+            list.to_vec()
         }
-    }
+        None => vec![],
+    };
+    let pos = ids.partition_point(|&x| x < id.0);
+    ids.insert(pos, id.0);
+    write_tagmap(root, name, &ids)
+}
 
-    let mut data = vec![];
-    file.read_to_end(&mut data).expect("could not read file");
-    let mut buf = id.0;
-    for i in 0..data.len() / 4 {
-        let v = read_int(&data, i);
-        if v < id.0 {
-            continue;
+pub fn remove_tag_from_map(root: &Path, name: &str, id: ID) -> Result<()> {
+    let mut ids = match open_tagmap(root, name)? {
+        Some(map) => {
+            let list = PostingList::new(&map)?;
+            if !list.contains(id) {
+                return Ok(());
+            }
+            list.to_vec()
         }
-        write_int(&mut data, i, buf);
-        buf = v;
-    }
-    data.extend_from_slice(&u32::to_le_bytes(buf));
+        None => return Ok(()),
+    };
+    ids.retain(|&x| x != id.0);
+    write_tagmap(root, name, &ids)
+}
 
-    file.seek(SeekFrom::Start(0)).expect("could not seek");
-    file.write_all(&data).expect("could not write");
+fn checked_tag_file(tag: &TagName) -> Result<String> {
+    tag_file(tag).ok_or_else(|| Error::InvalidTagName(tag.clone()))
 }
 
-pub fn remove_tag_from_map(root: &mut PathBuf, name: &str, id: ID) {
-    root.push(name);
-    let mut file = File::options()
-        .create(true)
-        .write(true)
-        .read(true)
-        .open(&root)
-        .expect("could not open allfile");
-    root.pop();
+pub fn add_tag(root: &mut PathBuf, tag: &TagName, value: &Value) -> Result<()> {
+    let tagfile = checked_tag_file(tag)?;
+    if value.0.len() > MAX_VALUE_LENGTH {
+        return Err(Error::ValueTooLong(value.0.len()));
+    }
+    let op = Op::AddTag(tag.clone(), value.clone());
+    journal::begin(root, &op)?;
+    apply_add_tag(root, tag, &tagfile, value)?;
+    journal::commit(root)
+}
 
-    {
-        let map = unsafe { memmap2::Mmap::map(&file).expect("could not memmap all file") };
+fn apply_add_tag(root: &mut PathBuf, tag: &TagName, tagfile: &str, value: &Value) -> Result<()> {
+    let (dataid, _) = insert_data(root, value)?;
 
-        if !find(&map, id) {
-            return;
-        }
-    }
+    // always make sure the value is in __all in case we were interrupted right after inserting it
+    insert_tag_in_map(root, "__all", dataid)?;
+    save_name(root, tagfile, &tag.0)?;
+    insert_tag_in_map(root, tagfile, dataid)?;
+    reverse::add(root, dataid, tag)
+}
 
-    let mut data = vec![];
-    file.read_to_end(&mut data).expect("could not read file");
-    let mut i = 0;
-    while i < data.len() / 4 {
-        let v = read_int(&data, i);
-        if v < id.0 {
-            i += 1;
-            continue;
-        }
-        if v == id.0 {
-            break;
+pub fn del_tag(root: &mut PathBuf, tag: &TagName, value: &Value) -> Result<()> {
+    let tagfile = checked_tag_file(tag)?;
+    let op = Op::DelTag(tag.clone(), value.clone());
+    journal::begin(root, &op)?;
+    apply_del_tag(root, tag, &tagfile, value)?;
+    journal::commit(root)
+}
+
+/// The ID of the value, None if it is not in the store.
+/// Never writes so it can run under a shared lock: values are scanned when the index is stale.
+pub fn find_value(root: &mut PathBuf, value: &Value) -> Result<Option<ID>> {
+    let (datamap, _) = get_datamap(root)?;
+    let (offsetmap, _) = get_offsetmap(root)?;
+    let needle = value.0.as_bytes();
+    let record = |off| record(&offsetmap, &datamap, off);
+    let n = n_values(&offsetmap);
+    let found = match Index::open_read(root, n)? {
+        Some(map) => index::lookup(&map, needle, record)?,
+        None => {
+            let mut found = None;
+            for off in 0..n {
+                if record(off)? == needle {
+                    found = Some(off);
+                    break;
+                }
+            }
+            found
         }
-    }
-    while i < data.len() / 4 {
-        let v = read_int(&data, i + 1);
-        write_int(&mut data, i, v);
-        i += 1;
-    }
-    if data.len() <= 4 {
-        root.push(name);
-        std::fs::remove_file(&root).expect("could not delete tagmap");
-        root.pop();
-        return;
-    }
-    file.set_len((data.len() - 4) as u64)
-        .expect("could not truncate tagmap");
+    };
+    Ok(found.map(|off| id_from_off(&offsetmap, off)))
 }
 
-pub fn add_tag(tag: &TagName, value: &Value) {
-    let mut root = getroot();
+fn apply_del_tag(root: &mut PathBuf, tag: &TagName, tagfile: &str, value: &Value) -> Result<()> {
+    let id = if let Some(x) = find_value(root, value)? {
+        x
+    } else {
+        return Ok(());
+    };
+
+    remove_tag_from_map(root, tagfile, id)?;
+    reverse::remove(root, id, tag)
+}
 
-    let (dataid, inserted) = insert_data(&mut root, &value);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{temp_store, Store};
 
-    if inserted {
-        insert_tag_in_map(&mut root, "__all", dataid);
+    fn tag(x: &str) -> TagName {
+        TagName(x.to_string())
     }
-    insert_tag_in_map(&mut root, &tag.0, dataid);
-}
 
-pub fn del_tag(tag: &TagName, value: &Value) {
-    if value.0.len() > MAX_VALUE_LENGTH {
-        panic!("value too big");
+    fn value(x: &str) -> Value {
+        Value(x.to_string())
     }
-    let mut root = getroot();
 
-    let (datamap, _) = get_datamap(&mut root);
-    let needle = prepare_data_needle(value);
+    fn values(store: &Store, qry: &str) -> Vec<String> {
+        let mut v: Vec<_> = store.query(qry, usize::MAX).unwrap();
+        v.sort_by(|a, b| a.0.cmp(&b.0));
+        v.into_iter().map(|x| x.0).collect()
+    }
 
-    let id = search_data(&datamap, &needle);
-    let id = if let Some(x) = id { x } else { return };
+    #[test]
+    fn replays_journal() {
+        let root = temp_store("replay-journal");
+        let store = Store::open(&root).unwrap();
+        store.add_tag(&tag("a"), &value("x")).unwrap();
+        store.add_tag(&tag("b"), &value("x")).unwrap();
+
+        // interrupted before anything was written
+        journal::begin(&root, &Op::AddTag(tag("a"), value("y"))).unwrap();
+        assert_eq!(values(&store, "a"), ["x", "y"]);
+        assert!(!root.join("__journal").exists());
+
+        // interrupted after the write completed
+        store.add_tag(&tag("c"), &value("x")).unwrap();
+        journal::begin(&root, &Op::AddTag(tag("c"), value("x"))).unwrap();
+        assert_eq!(values(&store, "c"), ["x"]);
+
+        journal::begin(&root, &Op::DelTag(tag("b"), value("x"))).unwrap();
+        assert_eq!(values(&store, "b"), Vec::<String>::new());
+        assert!(store.take_discarded_journal().is_none());
+        std::fs::remove_dir_all(root).unwrap();
+    }
 
-    remove_tag_from_map(&mut root, &tag.0, id);
+    #[test]
+    fn discards_corrupt_journal() {
+        let root = temp_store("corrupt-journal");
+        let store = Store::open(&root).unwrap();
+        store.add_tag(&tag("a"), &value("x")).unwrap();
+
+        std::fs::write(root.join("__journal"), b"A\x05\x00\x00\x00ab").unwrap();
+        assert_eq!(values(&store, "a"), ["x"]);
+        assert!(matches!(
+            store.take_discarded_journal(),
+            Some(Error::Corrupted(_))
+        ));
+        assert!(!root.join("__journal").exists());
+        assert!(store.take_discarded_journal().is_none());
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn drops_partial_offset_entry() {
+        let mut root = temp_store("partial-offset");
+        let store = Store::open(&root).unwrap();
+        store.add_tag(&tag("a"), &value("x")).unwrap();
+
+        let len = offsetfile_len(&mut root);
+        let mut offsets = File::options()
+            .append(true)
+            .open(root.join("__offsets"))
+            .unwrap();
+        offsets.write_all(&[1, 2, 3]).unwrap();
+        drop(offsets);
+        assert!(needs_recovery(&mut root));
+        assert_eq!(values(&store, "a"), ["x"]);
+        assert_eq!(offsetfile_len(&mut root), len);
+
+        store.add_tag(&tag("a"), &value("y")).unwrap();
+        assert_eq!(values(&store, "a"), ["x", "y"]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
 }